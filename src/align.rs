@@ -0,0 +1,484 @@
+//! Pairing of the frames of the two inputs.
+
+use std::path::Path;
+
+use av_metrics::video::{Frame, Pixel};
+use serde::Serialize;
+
+use crate::{
+    color::plane_samples,
+    decoder::{DecodedFrame, TimedFrame, VideoDecoder},
+    error::{Error, Result},
+    resample::{resample, Filter, Mapping},
+};
+
+/// How far apart, in seconds, the timestamps of frames may be and still be
+/// considered an exact match. This allows for the rounding of timestamps to
+/// the time base of the container.
+const EXACT_TOLERANCE: f64 = 0.001;
+
+/// The size luma planes are scaled to when looking for the offset between
+/// the inputs, which also lets inputs with different resolutions be compared.
+const THUMBNAIL_SIZE: (usize, usize) = (64, 64);
+
+/// How frames of the distorted input are paired with frames of the reference
+/// input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlignMode {
+    /// Pair frames in the order they are decoded.
+    Order,
+    /// Pair each reference frame with the distorted frame whose timestamp is
+    /// closest to it, within one frame.
+    Nearest,
+    /// Only pair frames whose timestamps match.
+    Exact,
+}
+
+impl AlignMode {
+    pub const NAMES: [&'static str; 3] = ["order", "nearest", "exact"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "order" => Some(AlignMode::Order),
+            "nearest" => Some(AlignMode::Nearest),
+            "exact" => Some(AlignMode::Exact),
+            _ => None,
+        }
+    }
+}
+
+/// The frames which could not be paired when aligning by timestamp. They are
+/// not scored.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlignmentReport {
+    pub mode: AlignMode,
+    /// Reference frames which have no distorted frame of their own.
+    pub dropped: Vec<usize>,
+    /// Distorted frames which do not match a reference frame of their own,
+    /// such as repeated frames.
+    pub duplicated: Vec<usize>,
+}
+
+pub enum NextPair {
+    Pair {
+        /// The presentation time of the reference frame, in seconds.
+        timestamp: f64,
+        reference: Box<DecodedFrame>,
+        distorted: Box<DecodedFrame>,
+    },
+    /// The reference frame has no distorted frame to be compared with.
+    Dropped,
+    /// The inputs are paired by decode order, and one of them ended early.
+    LengthMismatch,
+    End,
+}
+
+/// A source of frames and their timestamps, such as a [`VideoDecoder`].
+pub trait FrameSource {
+    fn read_frame(&mut self) -> Result<Option<TimedFrame>>;
+}
+
+impl FrameSource for VideoDecoder {
+    fn read_frame(&mut self) -> Result<Option<TimedFrame>> {
+        VideoDecoder::read_frame(self)
+    }
+}
+
+/// A distorted frame which may be paired with a reference frame.
+struct Candidate {
+    frameno: usize,
+    timestamp: f64,
+    /// The frame, until it has been paired.
+    frame: Option<DecodedFrame>,
+}
+
+/// Reads pairs of frames from the inputs, one reference frame at a time.
+pub struct FramePairer {
+    mode: AlignMode,
+    /// The furthest apart the timestamps of a pair may be, in seconds.
+    tolerance: f64,
+    /// The distorted frame nearest to the last reference frame, and the one
+    /// after it.
+    current: Option<Candidate>,
+    next: Option<Candidate>,
+    /// The reference frame after the current one, which has been read ahead.
+    upcoming: Option<TimedFrame>,
+    reference_frameno: usize,
+    distorted_frameno: usize,
+    dropped: Vec<usize>,
+    duplicated: Vec<usize>,
+}
+
+impl FramePairer {
+    /// `frame_duration` is the duration of a frame of the reference input,
+    /// in seconds.
+    pub fn new(mode: AlignMode, frame_duration: f64) -> Self {
+        FramePairer {
+            mode,
+            tolerance: match mode {
+                AlignMode::Exact => EXACT_TOLERANCE,
+                _ => frame_duration + EXACT_TOLERANCE,
+            },
+            current: None,
+            next: None,
+            upcoming: None,
+            reference_frameno: 0,
+            distorted_frameno: 0,
+            dropped: Vec::new(),
+            duplicated: Vec::new(),
+        }
+    }
+
+    pub fn next_pair(
+        &mut self,
+        reference: &mut impl FrameSource,
+        distorted: &mut impl FrameSource,
+    ) -> Result<NextPair> {
+        let next = match self.upcoming.take() {
+            Some(frame) => Some(frame),
+            None => reference.read_frame()?,
+        };
+        let Some(TimedFrame {
+            frame: reference_frame,
+            timestamp,
+        }) = next
+        else {
+            return self.finish(distorted);
+        };
+        let frameno = self.reference_frameno;
+        self.reference_frameno += 1;
+
+        if self.mode == AlignMode::Order {
+            return Ok(match distorted.read_frame()? {
+                Some(frame) => NextPair::Pair {
+                    timestamp,
+                    reference: Box::new(reference_frame),
+                    distorted: Box::new(frame.frame),
+                },
+                None => NextPair::LengthMismatch,
+            });
+        }
+
+        self.upcoming = reference.read_frame()?;
+        if self.current.is_none() {
+            self.current = self.read_candidate(distorted)?;
+        }
+        loop {
+            if self.next.is_none() {
+                self.next = self.read_candidate(distorted)?;
+            }
+            // Ties go to the later frame, allowing for rounding of timestamps
+            let closer = match (&self.current, &self.next) {
+                (Some(current), Some(next)) => {
+                    (next.timestamp - timestamp).abs()
+                        <= (current.timestamp - timestamp).abs() + EXACT_TOLERANCE
+                }
+                _ => false,
+            };
+            if !closer {
+                break;
+            }
+            let retired = std::mem::replace(&mut self.current, self.next.take());
+            self.retire(retired);
+        }
+
+        // A distorted frame which was already paired with an earlier
+        // reference frame is not compared again. One which is closer to the
+        // next reference frame is left for that frame, so that when every
+        // other frame was dropped, the remaining frames are not paired with
+        // the frame before the one they match.
+        let upcoming = self.upcoming.as_ref().map(|frame| frame.timestamp);
+        let matched = self
+            .current
+            .as_mut()
+            .filter(|current| {
+                let distance = (current.timestamp - timestamp).abs();
+                distance <= self.tolerance
+                    && upcoming.is_none_or(|upcoming| {
+                        distance <= (current.timestamp - upcoming).abs() + EXACT_TOLERANCE
+                    })
+            })
+            .and_then(|current| current.frame.take());
+        Ok(match matched {
+            Some(frame) => NextPair::Pair {
+                timestamp,
+                reference: Box::new(reference_frame),
+                distorted: Box::new(frame),
+            },
+            None => {
+                self.dropped.push(frameno);
+                NextPair::Dropped
+            }
+        })
+    }
+
+    /// The frames which could not be paired, if frames were aligned by
+    /// timestamp.
+    pub fn report(self) -> Option<AlignmentReport> {
+        (self.mode != AlignMode::Order).then_some(AlignmentReport {
+            mode: self.mode,
+            dropped: self.dropped,
+            duplicated: self.duplicated,
+        })
+    }
+
+    /// Handles the end of the reference input.
+    fn finish(&mut self, distorted: &mut impl FrameSource) -> Result<NextPair> {
+        if self.mode == AlignMode::Order {
+            return Ok(match distorted.read_frame()? {
+                Some(_) => NextPair::LengthMismatch,
+                None => NextPair::End,
+            });
+        }
+        let current = self.current.take();
+        self.retire(current);
+        let next = self.next.take();
+        self.retire(next);
+        while let Some(candidate) = self.read_candidate(distorted)? {
+            self.retire(Some(candidate));
+        }
+        Ok(NextPair::End)
+    }
+
+    fn read_candidate(&mut self, distorted: &mut impl FrameSource) -> Result<Option<Candidate>> {
+        let Some(frame) = distorted.read_frame()? else {
+            return Ok(None);
+        };
+        let frameno = self.distorted_frameno;
+        self.distorted_frameno += 1;
+        Ok(Some(Candidate {
+            frameno,
+            timestamp: frame.timestamp,
+            frame: Some(frame.frame),
+        }))
+    }
+
+    /// Records a distorted frame which will not be paired any more.
+    fn retire(&mut self, candidate: Option<Candidate>) {
+        if let Some(Candidate {
+            frameno,
+            frame: Some(_),
+            ..
+        }) = candidate
+        {
+            self.duplicated.push(frameno);
+        }
+    }
+}
+
+/// The offset between the inputs found by [`detect_offset`].
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DetectedOffset {
+    /// The number of frames to skip at the start of the first input.
+    pub offset1: usize,
+    /// The number of frames to skip at the start of the second input.
+    pub offset2: usize,
+    /// The mean luma PSNR of the frames in the window at this offset, in dB.
+    pub psnr: f64,
+}
+
+/// Finds the offset between the inputs which makes the first `window`
+/// frames of each most similar.
+///
+/// Offsets of up to half the window in either direction are tried, and
+/// compared by the PSNR of the downscaled luma planes.
+pub fn detect_offset(input1: &Path, input2: &Path, window: usize) -> Result<DetectedOffset> {
+    let thumbnails1 = read_thumbnails(input1, window)?;
+    let thumbnails2 = read_thumbnails(input2, window)?;
+    let frames = thumbnails1.len().min(thumbnails2.len());
+    if frames == 0 {
+        return Err(Error::NoFrames);
+    }
+    let max_offset = (frames / 2) as isize;
+
+    // Smaller offsets are tried first, so that they win ties
+    let mut offsets: Vec<isize> = (-max_offset..=max_offset).collect();
+    offsets.sort_by_key(|offset| offset.unsigned_abs());
+    let mut best: Option<(isize, f64)> = None;
+    for offset in offsets {
+        let (skip1, skip2) = (offset.min(0).unsigned_abs(), offset.max(0) as usize);
+        let pairs = thumbnails1
+            .iter()
+            .skip(skip1)
+            .zip(thumbnails2.iter().skip(skip2));
+        let (count, sum) = pairs.fold((0, 0.0), |(count, sum), (a, b)| {
+            (count + 1, sum + mean_squared_error(a, b))
+        });
+        let psnr = psnr(sum / count as f64);
+        if best.is_none_or(|(_, best_psnr)| psnr > best_psnr) {
+            best = Some((offset, psnr));
+        }
+    }
+    let (offset, psnr) = best.unwrap();
+    Ok(DetectedOffset {
+        offset1: offset.min(0).unsigned_abs(),
+        offset2: offset.max(0) as usize,
+        psnr,
+    })
+}
+
+/// Reads the luma planes of up to `count` frames, scaled to
+/// `THUMBNAIL_SIZE` and normalized to `0.0..=1.0`.
+fn read_thumbnails(input: &Path, count: usize) -> Result<Vec<Vec<f32>>> {
+    let mut decoder = VideoDecoder::new(input)?;
+    let details = decoder.video_details();
+    let size = (details.width, details.height);
+    let mut thumbnails = Vec::with_capacity(count);
+    while thumbnails.len() < count {
+        let Some(frame) = decoder.read_frame()? else {
+            break;
+        };
+        thumbnails.push(match frame.frame {
+            DecodedFrame::Low(frame) => thumbnail(&frame, size, details.bit_depth),
+            DecodedFrame::High(frame) => thumbnail(&frame, size, details.bit_depth),
+        });
+    }
+    Ok(thumbnails)
+}
+
+fn thumbnail<T: Pixel>(frame: &Frame<T>, size: (usize, usize), bit_depth: usize) -> Vec<f32> {
+    let max = ((1 << bit_depth) - 1) as f32;
+    let samples: Vec<f32> = plane_samples(&frame.planes[0], size)
+        .iter()
+        .map(|&v| v / max)
+        .collect();
+    let mapping = (
+        Mapping::scale(size.0, THUMBNAIL_SIZE.0),
+        Mapping::scale(size.1, THUMBNAIL_SIZE.1),
+    );
+    resample(&samples, size, THUMBNAIL_SIZE, mapping, Filter::Bilinear)
+}
+
+fn mean_squared_error(a: &[f32], b: &[f32]) -> f64 {
+    let sum: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(&a, &b)| ((a - b) as f64).powi(2))
+        .sum();
+    sum / a.len() as f64
+}
+
+/// The PSNR of samples normalized to `0.0..=1.0`, capped at 100 dB for
+/// identical frames.
+fn psnr(mse: f64) -> f64 {
+    (-10.0 * mse.log10()).min(100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use av_metrics::video::ChromaSampling;
+
+    use super::*;
+
+    /// Frames at the given timestamps, tagged with their index in the first
+    /// sample.
+    struct Timestamps(std::iter::Enumerate<std::vec::IntoIter<f64>>);
+
+    impl Timestamps {
+        fn new(timestamps: Vec<f64>) -> Self {
+            Timestamps(timestamps.into_iter().enumerate())
+        }
+    }
+
+    impl FrameSource for Timestamps {
+        fn read_frame(&mut self) -> Result<Option<TimedFrame>> {
+            let Some((index, timestamp)) = self.0.next() else {
+                return Ok(None);
+            };
+            let mut frame = Frame::new_with_padding(8, 8, ChromaSampling::Cs420, 0);
+            frame.planes[0].copy_from_raw_u8(&[index as u8], 1, 1);
+            Ok(Some(TimedFrame {
+                frame: DecodedFrame::Low(frame),
+                timestamp,
+            }))
+        }
+    }
+
+    fn at_fps(fps: f64, count: usize) -> Vec<f64> {
+        (0..count).map(|i| i as f64 / fps).collect()
+    }
+
+    /// Returns the indices of the paired reference and distorted frames.
+    fn align(
+        mode: AlignMode,
+        reference: Vec<f64>,
+        distorted: Vec<f64>,
+    ) -> (Vec<(usize, usize)>, Option<AlignmentReport>) {
+        let (mut reference, mut distorted) =
+            (Timestamps::new(reference), Timestamps::new(distorted));
+        let mut pairer = FramePairer::new(mode, 1.0 / 24.0);
+        let mut pairs = Vec::new();
+        for frameno in 0.. {
+            match pairer.next_pair(&mut reference, &mut distorted).unwrap() {
+                NextPair::Pair { distorted, .. } => match *distorted {
+                    DecodedFrame::Low(frame) => {
+                        pairs.push((frameno, frame.planes[0].p(0, 0) as usize))
+                    }
+                    DecodedFrame::High(_) => unreachable!(),
+                },
+                NextPair::Dropped => {}
+                NextPair::LengthMismatch | NextPair::End => break,
+            }
+        }
+        (pairs, pairer.report())
+    }
+
+    #[test]
+    fn order() {
+        let (pairs, report) = align(AlignMode::Order, at_fps(24.0, 3), at_fps(12.0, 2));
+        assert_eq!(pairs, [(0, 0), (1, 1)]);
+        assert!(report.is_none());
+    }
+
+    #[test]
+    fn nearest_with_every_other_frame_dropped() {
+        let (pairs, report) = align(AlignMode::Nearest, at_fps(24.0, 6), at_fps(12.0, 3));
+        assert_eq!(pairs, [(0, 0), (2, 1), (4, 2)]);
+        let report = report.unwrap();
+        assert_eq!(report.dropped, [1, 3, 5]);
+        assert!(report.duplicated.is_empty());
+    }
+
+    #[test]
+    fn nearest_with_repeated_frames() {
+        let (pairs, report) = align(AlignMode::Nearest, at_fps(24.0, 4), at_fps(48.0, 8));
+        assert_eq!(pairs, [(0, 0), (1, 2), (2, 4), (3, 6)]);
+        let report = report.unwrap();
+        assert!(report.dropped.is_empty());
+        assert_eq!(report.duplicated, [1, 3, 5, 7]);
+    }
+
+    #[test]
+    fn nearest_with_half_a_frame_between_inputs() {
+        let distorted = at_fps(24.0, 4).iter().map(|t| t + 0.5 / 24.0).collect();
+        let (pairs, report) = align(AlignMode::Nearest, at_fps(24.0, 4), distorted);
+        assert_eq!(pairs, [(0, 0), (1, 1), (2, 2), (3, 3)]);
+        let report = report.unwrap();
+        assert!(report.dropped.is_empty());
+        assert!(report.duplicated.is_empty());
+    }
+
+    #[test]
+    fn exact() {
+        let distorted = [0.0, 1.0, 3.0, 4.0].iter().map(|i| i / 24.0).collect();
+        let (pairs, report) = align(AlignMode::Exact, at_fps(24.0, 4), distorted);
+        assert_eq!(pairs, [(0, 0), (1, 1), (3, 2)]);
+        let report = report.unwrap();
+        assert_eq!(report.dropped, [2]);
+        assert_eq!(report.duplicated, [3]);
+    }
+
+    #[test]
+    fn exact_allows_rounded_timestamps() {
+        // 23.976 fps, with the distorted timestamps rounded to milliseconds
+        let reference = at_fps(24000.0 / 1001.0, 5);
+        let distorted = reference
+            .iter()
+            .map(|t| (t * 1000.0).round() / 1000.0)
+            .collect();
+        let (pairs, report) = align(AlignMode::Exact, reference, distorted);
+        assert_eq!(pairs.len(), 5);
+        assert!(report.unwrap().dropped.is_empty());
+    }
+}
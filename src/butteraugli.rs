@@ -0,0 +1,1125 @@
+//! A native implementation of the butteraugli metric.
+//!
+//! This follows libjxl's `lib/jxl/butteraugli/butteraugli.cc` with the defaults
+//! of its `butteraugli_main` tool. libjxl approximates `log2` with SIMD
+//! polynomials where this uses the exact function, so the ignored
+//! `matches_libjxl` test allows a relative difference of 0.1%.
+
+use image::{Rgb, RgbImage};
+
+use crate::linear_rgb::LinearRgb;
+
+/// The result of comparing a pair of frames.
+pub struct Butteraugli {
+    /// The butteraugli distance, which is the maximum of the distortion map.
+    pub score: f64,
+    pub norm: f64,
+    diffmap: Plane,
+}
+
+impl Butteraugli {
+    /// Renders the distortion map with the same colors as `butteraugli_main`.
+    pub fn heatmap(&self) -> RgbImage {
+        let good = fuzzy_inverse(1.5);
+        let bad = fuzzy_inverse(0.5);
+        RgbImage::from_fn(
+            self.diffmap.width as u32,
+            self.diffmap.height as u32,
+            |x, y| {
+                let score = self.diffmap.row(y as usize)[x as usize] as f64;
+                Rgb(score_to_rgb(score, good, bad))
+            },
+        )
+    }
+}
+
+/// Computes the butteraugli distance and 3-norm of `distorted` compared to
+/// `source`.
+///
+/// Both images are 16-bit sRGB, with interleaved RGB samples.
+/// `intensity_target` is the display brightness of white, in nits.
+pub fn compute_frame_butteraugli(
+    source: &[u16],
+    distorted: &[u16],
+    width: usize,
+    height: usize,
+    intensity_target: f32,
+) -> Butteraugli {
+    let rgb1 = LinearRgb::from_srgb(source, width, height);
+    let rgb2 = LinearRgb::from_srgb(distorted, width, height);
+    let diffmap = diffmap(&rgb1, &rgb2, intensity_target);
+    Butteraugli {
+        score: diffmap.data.iter().fold(0.0f32, |max, &v| max.max(v)) as f64,
+        norm: three_norm(&diffmap),
+        diffmap,
+    }
+}
+
+/// A single plane of samples.
+#[derive(Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        Plane {
+            width,
+            height,
+            data: vec![0.0; width * height],
+        }
+    }
+
+    fn from_vec(data: Vec<f32>, width: usize, height: usize) -> Self {
+        debug_assert_eq!(data.len(), width * height);
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn row(&self, y: usize) -> &[f32] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    fn map(&self, f: impl Fn(f32) -> f32) -> Self {
+        Plane::from_vec(
+            self.data.iter().map(|&v| f(v)).collect(),
+            self.width,
+            self.height,
+        )
+    }
+
+    fn zip_map(&self, other: &Plane, f: impl Fn(f32, f32) -> f32) -> Self {
+        Plane::from_vec(
+            self.data
+                .iter()
+                .zip(&other.data)
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+            self.width,
+            self.height,
+        )
+    }
+}
+
+/// The frequency bands an image is split into. Ultra high and high frequencies
+/// are only kept for the X and Y channels.
+struct PsychoImage {
+    uhf: [Plane; 2],
+    hf: [Plane; 2],
+    mf: [Plane; 3],
+    lf: [Plane; 3],
+}
+
+impl PsychoImage {
+    fn new(rgb: &LinearRgb, intensity_target: f32) -> Self {
+        separate_frequencies(opsin_dynamics_image(rgb, intensity_target))
+    }
+}
+
+/// The smallest width and height the distortion map is computed at.
+const MIN_SIZE: usize = 8;
+
+/// Computes the distortion map at full resolution, and blends in the map of
+/// the images at half resolution.
+fn diffmap(rgb1: &LinearRgb, rgb2: &LinearRgb, intensity_target: f32) -> Plane {
+    if rgb1.width < MIN_SIZE || rgb1.height < MIN_SIZE {
+        return diffmap_small(rgb1, rgb2, intensity_target);
+    }
+    let mut result = diffmap_psycho_image(
+        &PsychoImage::new(rgb1, intensity_target),
+        &PsychoImage::new(rgb2, intensity_target),
+    );
+
+    let sub1 = subsample_2x(rgb1);
+    if sub1.width < MIN_SIZE || sub1.height < MIN_SIZE {
+        return result;
+    }
+    let sub2 = subsample_2x(rgb2);
+    let subresult = diffmap_psycho_image(
+        &PsychoImage::new(&sub1, intensity_target),
+        &PsychoImage::new(&sub2, intensity_target),
+    );
+    add_supersampled_2x(&subresult, 0.5, &mut result);
+    result
+}
+
+/// Computes the distortion map of images smaller than [`MIN_SIZE`] like
+/// libjxl does, by extending their borders to [`MIN_SIZE`] and cropping the
+/// map of the extended images.
+fn diffmap_small(rgb1: &LinearRgb, rgb2: &LinearRgb, intensity_target: f32) -> Plane {
+    let (width, height) = (rgb1.width, rgb1.height);
+    let x_border = MIN_SIZE.saturating_sub(width) / 2;
+    let y_border = MIN_SIZE.saturating_sub(height) / 2;
+    let (padded_width, padded_height) = (width.max(MIN_SIZE), height.max(MIN_SIZE));
+    let pad = |rgb: &LinearRgb| LinearRgb {
+        width: padded_width,
+        height: padded_height,
+        planes: rgb.planes.each_ref().map(|plane| {
+            let mut out = Vec::with_capacity(padded_width * padded_height);
+            for y in 0..padded_height {
+                let y = y.saturating_sub(y_border).min(height - 1);
+                for x in 0..padded_width {
+                    let x = x.saturating_sub(x_border).min(width - 1);
+                    out.push(plane[y * width + x]);
+                }
+            }
+            out
+        }),
+    };
+
+    let padded = diffmap(&pad(rgb1), &pad(rgb2), intensity_target);
+    let mut result = Vec::with_capacity(width * height);
+    for y in 0..height {
+        result.extend_from_slice(&padded.row(y + y_border)[x_border..x_border + width]);
+    }
+    Plane::from_vec(result, width, height)
+}
+
+fn subsample_2x(rgb: &LinearRgb) -> LinearRgb {
+    let out_width = rgb.width.div_ceil(2);
+    let out_height = rgb.height.div_ceil(2);
+    let planes = rgb.planes.each_ref().map(|plane| {
+        let mut out = vec![0.0f32; out_width * out_height];
+        for y in 0..rgb.height {
+            for x in 0..rgb.width {
+                out[(y / 2) * out_width + x / 2] += 0.25 * plane[y * rgb.width + x];
+            }
+        }
+        if !rgb.width.is_multiple_of(2) {
+            for row in out.chunks_exact_mut(out_width) {
+                row[out_width - 1] *= 2.0;
+            }
+        }
+        if !rgb.height.is_multiple_of(2) {
+            for val in &mut out[(out_height - 1) * out_width..] {
+                *val *= 2.0;
+            }
+        }
+        out
+    });
+    LinearRgb {
+        width: out_width,
+        height: out_height,
+        planes,
+    }
+}
+
+fn add_supersampled_2x(src: &Plane, weight: f32, dest: &mut Plane) {
+    const HEURISTIC_MIXING_VALUE: f32 = 0.3;
+    for y in 0..dest.height {
+        let src_row = src.row(y / 2);
+        let dest_row = &mut dest.data[y * dest.width..(y + 1) * dest.width];
+        for (x, val) in dest_row.iter_mut().enumerate() {
+            *val *= 1.0 - HEURISTIC_MIXING_VALUE * weight;
+            *val += weight * src_row[x / 2];
+        }
+    }
+}
+
+/// Models the absorbance of the photopsins of the cones.
+fn opsin_absorbance([r, g, b]: [f32; 3]) -> [f32; 3] {
+    const MIX: [f32; 12] = [
+        0.299_565_5,
+        0.633_730_9,
+        0.077_705_62,
+        1.755_748_4,
+        0.221_586_9,
+        0.693_913_9,
+        0.098_731_36,
+        1.755_748_4,
+        0.02,
+        0.02,
+        0.204_801_3,
+        12.226_455,
+    ];
+    [
+        MIX[0] * r + MIX[1] * g + MIX[2] * b + MIX[3],
+        MIX[4] * r + MIX[5] * g + MIX[6] * b + MIX[7],
+        MIX[8] * r + MIX[9] * g + MIX[10] * b + MIX[11],
+    ]
+}
+
+fn gamma(v: f32) -> f32 {
+    19.245_013 * (v + 9.971_064).ln() - 23.160_463
+}
+
+/// Converts linear RGB to butteraugli's XYB, with the sensitivity of each
+/// pixel adapted to the brightness of its surroundings.
+fn opsin_dynamics_image(rgb: &LinearRgb, intensity_target: f32) -> [Plane; 3] {
+    const MIN: f32 = 1e-4;
+    let (width, height) = (rgb.width, rgb.height);
+    let [r, g, b] = rgb
+        .planes
+        .each_ref()
+        .map(|plane| Plane::from_vec(plane.clone(), width, height));
+    let blurred = [&r, &g, &b].map(|plane| blur(plane, 1.2));
+
+    let mut xyb = [
+        Plane::new(width, height),
+        Plane::new(width, height),
+        Plane::new(width, height),
+    ];
+    for i in 0..width * height {
+        let pre_mixed = opsin_absorbance(blurred.each_ref().map(|p| p.data[i] * intensity_target))
+            .map(|v| v.max(MIN));
+        let sensitivity = pre_mixed.map(|v| (gamma(v) / v).max(MIN));
+        let cur_mixed =
+            opsin_absorbance([r.data[i], g.data[i], b.data[i]].map(|v| v * intensity_target));
+        let cur_mixed = [
+            (cur_mixed[0] * sensitivity[0]).max(1.755_748_4),
+            (cur_mixed[1] * sensitivity[1]).max(1.755_748_4),
+            (cur_mixed[2] * sensitivity[2]).max(12.226_455),
+        ];
+        xyb[0].data[i] = 0.5 * (cur_mixed[0] - cur_mixed[1]);
+        xyb[1].data[i] = 0.5 * (cur_mixed[0] + cur_mixed[1]);
+        xyb[2].data[i] = cur_mixed[2];
+    }
+    xyb
+}
+
+fn remove_range_around_zero(w: f32, x: f32) -> f32 {
+    if x > w {
+        x - w
+    } else if x < -w {
+        x + w
+    } else {
+        0.0
+    }
+}
+
+fn amplify_range_around_zero(w: f32, x: f32) -> f32 {
+    if x > w {
+        x + w
+    } else if x < -w {
+        x - w
+    } else {
+        2.0 * x
+    }
+}
+
+fn maximum_clamp(v: f32, max: f32) -> f32 {
+    const MUL: f32 = 0.724_216_15;
+    if v >= max {
+        (v - max) * MUL + max
+    } else if v < -max {
+        (v + max) * MUL - max
+    } else {
+        v
+    }
+}
+
+/// Splits an XYB image into frequency bands.
+fn separate_frequencies(xyb: [Plane; 3]) -> PsychoImage {
+    const SIGMA_LF: f32 = 7.155_933_4;
+    const SIGMA_HF: f32 = 3.224_899;
+    const SIGMA_UHF: f32 = 1.564_163_3;
+
+    let lf = xyb.each_ref().map(|plane| blur(plane, SIGMA_LF));
+    let mut mf: [Plane; 3] = [0, 1, 2].map(|c| xyb[c].zip_map(&lf[c], |v, lf| v - lf));
+    let mut hf = [mf[0].clone(), mf[1].clone()];
+    for c in 0..3 {
+        mf[c] = blur(&mf[c], SIGMA_HF);
+        if c == 2 {
+            break;
+        }
+        hf[c] = hf[c].zip_map(&mf[c], |hf, mf| hf - mf);
+        mf[c] = if c == 0 {
+            mf[c].map(|v| remove_range_around_zero(0.29, v))
+        } else {
+            mf[c].map(|v| amplify_range_around_zero(0.1, v))
+        };
+    }
+
+    // Suppress red-green by intensity change in the high frequencies
+    const SUPPRESS: f32 = 46.0;
+    const S: f32 = 0.653_020_56;
+    hf[0] = hf[0].zip_map(&hf[1], |x, y| {
+        let scaler = SUPPRESS / (y * y + SUPPRESS) * (1.0 - S) + S;
+        scaler * x
+    });
+
+    let mut uhf = [hf[0].clone(), hf[1].clone()];
+    for c in 0..2 {
+        hf[c] = blur(&hf[c], SIGMA_UHF);
+        if c == 0 {
+            uhf[c] = uhf[c].zip_map(&hf[c], |uhf, hf| remove_range_around_zero(0.04, uhf - hf));
+            hf[c] = hf[c].map(|v| remove_range_around_zero(1.5, v));
+        } else {
+            hf[c] = hf[c].map(|v| maximum_clamp(v, 28.469_18));
+            uhf[c] = uhf[c].zip_map(&hf[c], |uhf, hf| {
+                maximum_clamp(uhf - hf, 5.191_753) * 2.693_137_7
+            });
+            hf[c] = hf[c].map(|v| amplify_range_around_zero(0.132, v * 2.155));
+        }
+    }
+
+    // Scale the low frequencies so that a simple squared difference can be used
+    let [x, y, b] = lf;
+    let lf = [
+        x.map(|v| v * 32.221_75),
+        y.map(|v| v * 13.769_779),
+        b.zip_map(&y, |b, y| (b + y * -0.362_267_05) * 47.504_616),
+    ];
+
+    PsychoImage { uhf, hf, mf, lf }
+}
+
+/// Combines the differences of every frequency band into the distortion map.
+fn diffmap_psycho_image(pi0: &PsychoImage, pi1: &PsychoImage) -> Plane {
+    const W_UHF_MALTA: f64 = 1.100_390_325_55;
+    const NORM1_UHF: f64 = 71.780_027_516_9;
+    const W_UHF_MALTA_X: f64 = 173.5;
+    const NORM1_UHF_X: f64 = 5.0;
+    const W_HF_MALTA: f64 = 18.723_741_438_7;
+    const NORM1_HF: f64 = 4_498_534.452_32;
+    const W_HF_MALTA_X: f64 = 6_923.994_761_09;
+    const NORM1_HF_X: f64 = 8_051.158_332_47;
+    const W_MF_MALTA: f64 = 37.081_987_039_9;
+    const NORM1_MF: f64 = 130_262_059.556;
+    const W_MF_MALTA_X: f64 = 8_246.753_213_53;
+    const NORM1_MF_X: f64 = 1_009_002.705_82;
+    const WMUL: [f32; 9] = [
+        400.0,
+        1.508_157,
+        0.0,
+        2_150.0,
+        10.619_543,
+        16.217_604,
+        29.235_38,
+        0.844_626_97,
+        0.703_646_6,
+    ];
+
+    let (width, height) = (pi0.lf[0].width, pi0.lf[0].height);
+    let mut block_diff_ac = [
+        Plane::new(width, height),
+        Plane::new(width, height),
+        Plane::new(width, height),
+    ];
+
+    // The Malta filter is applied to the high frequency bands with more taps
+    const MULLI_HF: f64 = 0.399_058_176_37;
+    const MULLI_LF: f64 = 0.611_612_573_796;
+    let malta = [
+        (
+            &pi0.uhf[1],
+            &pi1.uhf[1],
+            W_UHF_MALTA,
+            NORM1_UHF,
+            1,
+            MULLI_HF,
+            &MALTA_HF,
+        ),
+        (
+            &pi0.uhf[0],
+            &pi1.uhf[0],
+            W_UHF_MALTA_X,
+            NORM1_UHF_X,
+            0,
+            MULLI_HF,
+            &MALTA_HF,
+        ),
+        (
+            &pi0.hf[1], &pi1.hf[1], W_HF_MALTA, NORM1_HF, 1, MULLI_LF, &MALTA_LF,
+        ),
+        (
+            &pi0.hf[0],
+            &pi1.hf[0],
+            W_HF_MALTA_X,
+            NORM1_HF_X,
+            0,
+            MULLI_LF,
+            &MALTA_LF,
+        ),
+        (
+            &pi0.mf[1], &pi1.mf[1], W_MF_MALTA, NORM1_MF, 1, MULLI_LF, &MALTA_LF,
+        ),
+        (
+            &pi0.mf[0],
+            &pi1.mf[0],
+            W_MF_MALTA_X,
+            NORM1_MF_X,
+            0,
+            MULLI_LF,
+            &MALTA_LF,
+        ),
+    ];
+    for (lum0, lum1, w, norm1, c, mulli, patterns) in malta {
+        malta_diff_map(lum0, lum1, w, norm1, mulli, patterns, &mut block_diff_ac[c]);
+    }
+
+    let block_diff_dc: [Plane; 3] =
+        [0, 1, 2].map(|c| pi0.lf[c].zip_map(&pi1.lf[c], |a, b| WMUL[6 + c] * (a - b) * (a - b)));
+    for c in 0..3 {
+        if c < 2 {
+            l2_diff_asymmetric(&pi0.hf[c], &pi1.hf[c], WMUL[c], &mut block_diff_ac[c]);
+        }
+        for ((out, a), b) in block_diff_ac[c]
+            .data
+            .iter_mut()
+            .zip(&pi0.mf[c].data)
+            .zip(&pi1.mf[c].data)
+        {
+            *out += WMUL[3 + c] * (a - b) * (a - b);
+        }
+    }
+
+    let mask = mask_psycho_image(pi0, pi1, &mut block_diff_ac[1]);
+    combine_channels_to_diffmap(&mask, &block_diff_dc, &block_diff_ac)
+}
+
+/// Adds the squared difference of two planes, with an additional penalty when
+/// the distorted values are too small or too large compared to the reference.
+fn l2_diff_asymmetric(i0: &Plane, i1: &Plane, w: f32, out: &mut Plane) {
+    if w == 0.0 {
+        return;
+    }
+    let w = w * 0.8;
+    for ((out, &val0), &val1) in out.data.iter_mut().zip(&i0.data).zip(&i1.data) {
+        let diff = val0 - val1;
+        let mut total = *out + diff * diff * w;
+
+        let fabs0 = val0.abs();
+        let too_small = 0.4 * fabs0;
+        let too_big = fabs0;
+        let v = if val0 < 0.0 {
+            if val1 > -too_small {
+                val1 + too_small
+            } else if val1 < -too_big {
+                -val1 - too_big
+            } else {
+                0.0
+            }
+        } else if val1 < too_small {
+            too_small - val1
+        } else if val1 > too_big {
+            val1 - too_big
+        } else {
+            0.0
+        };
+        total += w * v * v;
+        *out = total;
+    }
+}
+
+/// The lines along which the Malta filter sums differences, as `(x, y)`
+/// offsets.
+type MaltaPatterns = [&'static [(isize, isize)]; 16];
+
+const MALTA_HF: MaltaPatterns = [
+    &[
+        (-4, 0),
+        (-3, 0),
+        (-2, 0),
+        (-1, 0),
+        (0, 0),
+        (1, 0),
+        (2, 0),
+        (3, 0),
+        (4, 0),
+    ],
+    &[
+        (0, -4),
+        (0, -3),
+        (0, -2),
+        (0, -1),
+        (0, 0),
+        (0, 1),
+        (0, 2),
+        (0, 3),
+        (0, 4),
+    ],
+    &[(-3, -3), (-2, -2), (-1, -1), (0, 0), (1, 1), (2, 2), (3, 3)],
+    &[(3, -3), (2, -2), (1, -1), (0, 0), (-1, 1), (-2, 2), (-3, 3)],
+    &[
+        (1, -4),
+        (1, -3),
+        (1, -2),
+        (0, -1),
+        (0, 0),
+        (0, 1),
+        (-1, 2),
+        (-1, 3),
+        (-1, 4),
+    ],
+    &[
+        (-1, -4),
+        (-1, -3),
+        (-1, -2),
+        (0, -1),
+        (0, 0),
+        (0, 1),
+        (1, 2),
+        (1, 3),
+        (1, 4),
+    ],
+    &[
+        (-4, -1),
+        (-3, -1),
+        (-2, -1),
+        (-1, 0),
+        (0, 0),
+        (1, 0),
+        (2, 1),
+        (3, 1),
+        (4, 1),
+    ],
+    &[
+        (-4, 1),
+        (-3, 1),
+        (-2, 1),
+        (-1, 0),
+        (0, 0),
+        (1, 0),
+        (2, -1),
+        (3, -1),
+        (4, -1),
+    ],
+    &[(-2, -3), (-1, -2), (-1, -1), (0, 0), (1, 1), (1, 2), (2, 3)],
+    &[(2, -3), (1, -2), (1, -1), (0, 0), (-1, 1), (-1, 2), (-2, 3)],
+    &[(-3, -2), (-2, -1), (-1, -1), (0, 0), (1, 1), (2, 1), (3, 2)],
+    &[(3, -2), (2, -1), (1, -1), (0, 0), (-1, 1), (-2, 1), (-3, 2)],
+    &[
+        (-4, 2),
+        (-3, 2),
+        (-2, 1),
+        (-1, 1),
+        (0, 0),
+        (1, 0),
+        (2, -1),
+        (3, -1),
+    ],
+    &[
+        (-4, -2),
+        (-3, -2),
+        (-2, -1),
+        (-1, -1),
+        (0, 0),
+        (1, 0),
+        (2, 1),
+        (3, 1),
+    ],
+    &[
+        (-2, -4),
+        (-2, -3),
+        (-1, -2),
+        (-1, -1),
+        (0, 0),
+        (0, 1),
+        (1, 2),
+        (1, 3),
+    ],
+    &[
+        (2, -4),
+        (2, -3),
+        (1, -2),
+        (1, -1),
+        (0, 0),
+        (0, 1),
+        (-1, 2),
+        (-1, 3),
+    ],
+];
+
+const MALTA_LF: MaltaPatterns = [
+    &[(-4, 0), (-2, 0), (0, 0), (2, 0), (4, 0)],
+    &[(0, -4), (0, -2), (0, 0), (0, 2), (0, 4)],
+    &[(-3, -3), (-2, -2), (0, 0), (2, 2), (3, 3)],
+    &[(3, -3), (2, -2), (0, 0), (-2, 2), (-3, 3)],
+    &[(1, -4), (1, -2), (0, 0), (-1, 2), (-1, 4)],
+    &[(-1, -4), (-1, -2), (0, 0), (1, 2), (1, 4)],
+    &[(-4, -1), (-2, -1), (0, 0), (2, 1), (4, 1)],
+    &[(-4, 1), (-2, 1), (0, 0), (2, -1), (4, -1)],
+    &[(-2, -3), (-1, -2), (0, 0), (1, 2), (2, 3)],
+    &[(2, -3), (1, -2), (0, 0), (-1, 2), (-2, 3)],
+    &[(-3, -2), (-2, -1), (0, 0), (2, 1), (3, 2)],
+    &[(3, -2), (2, -1), (0, 0), (-2, 1), (-3, 2)],
+    &[(-4, 2), (-2, 1), (0, 0), (2, -1), (4, -2)],
+    &[(-4, -2), (-2, -1), (0, 0), (2, 1), (4, 2)],
+    &[(-2, -4), (-1, -2), (0, 0), (1, 2), (2, 4)],
+    &[(2, -4), (1, -2), (0, 0), (-1, 2), (-2, 4)],
+];
+
+/// Adds the Malta filtered differences of two planes, which penalizes
+/// differences that line up along short lines more than scattered ones.
+fn malta_diff_map(
+    lum0: &Plane,
+    lum1: &Plane,
+    w: f64,
+    norm1: f64,
+    mulli: f64,
+    patterns: &MaltaPatterns,
+    out: &mut Plane,
+) {
+    const LEN: f64 = 3.75;
+    const WEIGHT0: f64 = 0.5;
+    const WEIGHT1: f64 = 0.33;
+    let w_pre0gt1 = mulli * (WEIGHT0 * w).sqrt() / (LEN * 2.0 + 1.0);
+    let w_pre0lt1 = mulli * (WEIGHT1 * w).sqrt() / (LEN * 2.0 + 1.0);
+    let norm2_0gt1 = (w_pre0gt1 * norm1) as f32;
+    let norm2_0lt1 = (w_pre0lt1 * norm1) as f32;
+    let norm1 = norm1 as f32;
+
+    let diffs = lum0.zip_map(lum1, |val0, val1| {
+        let absval = 0.5 * (val0.abs() + val1.abs());
+        let diff = val0 - val1;
+        let scaler = norm2_0gt1 / (norm1 + absval);
+
+        // Primary symmetric quadratic objective
+        let mut result = scaler * diff;
+
+        // Secondary half-open quadratic objectives
+        let scaler2 = norm2_0lt1 / (norm1 + absval);
+        let fabs0 = val0.abs();
+        let too_small = 0.55 * fabs0;
+        let too_big = 1.05 * fabs0;
+        if val0 < 0.0 {
+            if val1 > -too_small {
+                result -= scaler2 * (val1 + too_small);
+            } else if val1 < -too_big {
+                result += scaler2 * (-val1 - too_big);
+            }
+        } else if val1 < too_small {
+            result += scaler2 * (too_small - val1);
+        } else if val1 > too_big {
+            result -= scaler2 * (val1 - too_big);
+        }
+        result
+    });
+
+    let (width, height) = (diffs.width as isize, diffs.height as isize);
+    for y in 0..height {
+        for x in 0..width {
+            let interior = x >= 4 && y >= 4 && x < width - 4 && y < height - 4;
+            let mut total = 0.0f32;
+            for pattern in patterns {
+                let sum: f32 = if interior {
+                    pattern
+                        .iter()
+                        .map(|&(dx, dy)| diffs.data[((y + dy) * width + x + dx) as usize])
+                        .sum()
+                } else {
+                    // Samples outside of the image are zero
+                    pattern
+                        .iter()
+                        .filter_map(|&(dx, dy)| {
+                            let (sx, sy) = (x + dx, y + dy);
+                            (sx >= 0 && sy >= 0 && sx < width && sy < height)
+                                .then(|| diffs.data[(sy * width + sx) as usize])
+                        })
+                        .sum()
+                };
+                total += sum * sum;
+            }
+            out.data[(y * width + x) as usize] += total;
+        }
+    }
+}
+
+/// Computes the visual masking of every pixel from the high frequencies of the
+/// reference, and adds the difference in masking between the images to the Y
+/// channel of `diff_ac`.
+fn mask_psycho_image(pi0: &PsychoImage, pi1: &PsychoImage, diff_ac: &mut Plane) -> Plane {
+    const MULS: [f32; 3] = [2.5, 0.4, 0.4];
+    const MUL: f32 = 6.194_240_8;
+    const BIAS: f32 = 12.610_506;
+    const RADIUS: f32 = 2.7;
+    const MASK_TO_ERROR_MUL: f32 = 10.0;
+
+    let [blurred0, blurred1] = [pi0, pi1].map(|pi| {
+        let mut mask = Plane::new(pi.hf[0].width, pi.hf[0].height);
+        for (i, out) in mask.data.iter_mut().enumerate() {
+            let xdiff = (pi.uhf[0].data[i] + pi.hf[0].data[i]) * MULS[0];
+            let ydiff = pi.uhf[1].data[i] * MULS[1] + pi.hf[1].data[i] * MULS[2];
+            let val = (xdiff * xdiff + ydiff * ydiff).sqrt();
+            // The bias makes the square root behave more linearly
+            let bias = MUL * BIAS;
+            *out = (MUL * val.abs() + bias).sqrt() - bias.sqrt();
+        }
+        blur(&mask, RADIUS)
+    });
+
+    for ((out, a), b) in diff_ac
+        .data
+        .iter_mut()
+        .zip(&blurred0.data)
+        .zip(&blurred1.data)
+    {
+        *out += MASK_TO_ERROR_MUL * (a - b) * (a - b);
+    }
+    fuzzy_erosion(&blurred0)
+}
+
+/// Replaces every value with a weighted sum of the smallest values in its
+/// neighbourhood.
+fn fuzzy_erosion(from: &Plane) -> Plane {
+    const STEP: usize = 3;
+    let (width, height) = (from.width, from.height);
+    let mut to = Plane::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let at = |x: usize, y: usize| from.data[y * width + x];
+            let mut min0 = at(x, y);
+            let mut min1 = 2.0 * min0;
+            let mut min2 = min1;
+            let mut store_min3 = |v: f32| {
+                if v < min2 {
+                    if v < min0 {
+                        min2 = min1;
+                        min1 = min0;
+                        min0 = v;
+                    } else if v < min1 {
+                        min2 = min1;
+                        min1 = v;
+                    } else {
+                        min2 = v;
+                    }
+                }
+            };
+            let xs = [x.checked_sub(STEP), Some(x), Some(x + STEP)];
+            let ys = [y.checked_sub(STEP), Some(y), Some(y + STEP)];
+            for sx in xs.into_iter().flatten().filter(|&sx| sx < width) {
+                for sy in ys.into_iter().flatten().filter(|&sy| sy < height) {
+                    if (sx, sy) != (x, y) {
+                        store_min3(at(sx, sy));
+                    }
+                }
+            }
+            to.data[y * width + x] = 0.45 * min0 + 0.3 * min1 + 0.25 * min2;
+        }
+    }
+    to
+}
+
+const GLOBAL_SCALE: f64 = 1.0 / 17.83;
+
+fn mask_y(delta: f64) -> f64 {
+    const OFFSET: f64 = 0.829_591_754_942;
+    const SCALER: f64 = 0.451_936_922_203;
+    const MUL: f64 = 2.548_594_479_3;
+    let c = MUL / (SCALER * delta + OFFSET);
+    let retval = GLOBAL_SCALE * (1.0 + c);
+    retval * retval
+}
+
+fn mask_dc_y(delta: f64) -> f64 {
+    const OFFSET: f64 = 0.200_255_785_22;
+    const SCALER: f64 = 3.874_494_188_04;
+    const MUL: f64 = 0.505_054_525_019;
+    let c = MUL / (SCALER * delta + OFFSET);
+    let retval = GLOBAL_SCALE * (1.0 + c);
+    retval * retval
+}
+
+fn combine_channels_to_diffmap(
+    mask: &Plane,
+    block_diff_dc: &[Plane; 3],
+    block_diff_ac: &[Plane; 3],
+) -> Plane {
+    let mut result = Plane::new(mask.width, mask.height);
+    for (i, out) in result.data.iter_mut().enumerate() {
+        let val = mask.data[i] as f64;
+        let maskval = mask_y(val) as f32;
+        let dc_maskval = mask_dc_y(val) as f32;
+        let diff_dc: f32 = block_diff_dc.iter().map(|plane| plane.data[i]).sum();
+        let diff_ac: f32 = block_diff_ac.iter().map(|plane| plane.data[i]).sum();
+        *out = (dc_maskval * diff_dc + maskval * diff_ac).sqrt();
+    }
+    result
+}
+
+/// The average of the 3-, 6- and 12-norms of the distortion map, which is what
+/// `butteraugli_main` reports as the 3-norm.
+fn three_norm(diffmap: &Plane) -> f64 {
+    let mut sums = [0.0f64; 3];
+    for &v in &diffmap.data {
+        let d3 = (v as f64).powi(3);
+        let d6 = d3 * d3;
+        sums[0] += d3;
+        sums[1] += d6;
+        sums[2] += d6 * d6;
+    }
+    let one_per_pixels = 1.0 / diffmap.data.len() as f64;
+    let v: f64 = sums
+        .iter()
+        .zip([3.0, 6.0, 12.0])
+        .map(|(sum, p)| (one_per_pixels * sum).powf(1.0 / p))
+        .sum();
+    v / 3.0
+}
+
+/// A Gaussian blur which renormalizes the kernel at the edges of the image.
+fn blur(plane: &Plane, sigma: f32) -> Plane {
+    let kernel = gaussian_kernel(sigma);
+    if kernel.len() == 5 {
+        return blur_5x5(plane, &kernel);
+    }
+    convolve_transposed(&convolve_transposed(plane, &kernel), &kernel)
+}
+
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    const M: f32 = 2.25;
+    let scaler = -1.0 / (2.0 * sigma as f64 * sigma as f64);
+    let diff = ((M * sigma.abs()) as isize).max(1);
+    (-diff..=diff)
+        .map(|i| (scaler * (i * i) as f64).exp() as f32)
+        .collect()
+}
+
+/// Convolves every row with `kernel`, and returns the result transposed.
+fn convolve_transposed(input: &Plane, kernel: &[f32]) -> Plane {
+    let offset = kernel.len() / 2;
+    let (width, height) = (input.width, input.height);
+    let ranges: Vec<_> = (0..width)
+        .map(|x| {
+            let min = x.saturating_sub(offset);
+            let max = (x + offset).min(width - 1);
+            let weights = &kernel[min + offset - x..=max + offset - x];
+            (min, weights, 1.0 / weights.iter().sum::<f32>())
+        })
+        .collect();
+
+    let mut out = Plane::new(height, width);
+    for y in 0..height {
+        let row = input.row(y);
+        for (x, &(min, weights, scale)) in ranges.iter().enumerate() {
+            let sum: f32 = row[min..]
+                .iter()
+                .zip(weights)
+                .map(|(val, weight)| val * weight)
+                .sum();
+            out.data[x * height + y] = sum * scale;
+        }
+    }
+    out
+}
+
+/// Convolves with a 5x5 separable kernel, mirroring the image at the edges.
+fn blur_5x5(input: &Plane, kernel: &[f32]) -> Plane {
+    let sum: f32 = kernel.iter().sum();
+    let weights: Vec<f32> = kernel.iter().map(|w| w / sum).collect();
+    let (width, height) = (input.width, input.height);
+
+    let mut horizontal = Plane::new(width, height);
+    for y in 0..height {
+        let row = input.row(y);
+        for x in 0..width {
+            horizontal.data[y * width + x] = weights
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * row[mirror(x as isize + k as isize - 2, width)])
+                .sum();
+        }
+    }
+    let mut out = Plane::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            out.data[y * width + x] = weights
+                .iter()
+                .enumerate()
+                .map(|(k, w)| {
+                    w * horizontal.data[mirror(y as isize + k as isize - 2, height) * width + x]
+                })
+                .sum();
+        }
+    }
+    out
+}
+
+fn mirror(mut x: isize, size: usize) -> usize {
+    let size = size as isize;
+    while x < 0 || x >= size {
+        if x < 0 {
+            x = -x - 1;
+        } else {
+            x = 2 * size - 1 - x;
+        }
+    }
+    x as usize
+}
+
+/// Maps a distance to a value which is about 2 for imperceptible differences,
+/// 1 for just noticeable differences, and 0 for clearly visible differences.
+fn fuzzy_class(score: f64) -> f64 {
+    const FUZZY_WIDTH: f64 = 4.8;
+    const M0: f64 = 2.0;
+    const SCALER: f64 = 0.7777;
+    let val = M0 / (1.0 + ((score - 1.0) * FUZZY_WIDTH).exp());
+    if score < 1.0 {
+        (val - 1.0) * (2.0 - SCALER) + SCALER
+    } else {
+        val * SCALER
+    }
+}
+
+fn fuzzy_inverse(seek: f64) -> f64 {
+    let mut pos = 0.0;
+    let mut range = 1.0;
+    while range >= 1e-10 {
+        if fuzzy_class(pos) < seek {
+            pos -= range;
+        } else {
+            pos += range;
+        }
+        range *= 0.5;
+    }
+    pos
+}
+
+fn score_to_rgb(score: f64, good: f64, bad: f64) -> [u8; 3] {
+    const HEATMAP: [[f64; 3]; 12] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, 1.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 0.0, 1.0],
+        [0.5, 0.5, 1.0],
+        [1.0, 0.5, 0.5],
+        [1.0, 1.0, 0.5],
+        [1.0, 1.0, 1.0],
+        [1.0, 1.0, 1.0],
+    ];
+    let score = if score < good {
+        (score / good) * 0.3
+    } else if score < bad {
+        0.3 + (score - good) / (bad - good) * 0.15
+    } else {
+        0.45 + (score - bad) / (bad * 12.0) * 0.5
+    };
+    let last = HEATMAP.len() - 1;
+    let score = (score * last as f64).clamp(0.0, (last - 1) as f64);
+    let ix = (score as usize).min(last - 1);
+    let mix = score - ix as f64;
+    [0, 1, 2].map(|i| {
+        let v = mix * HEATMAP[ix + 1][i] + (1.0 - mix) * HEATMAP[ix][i];
+        (v.sqrt() * 255.0).round() as u8
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Rgb16Image,
+        test_fixtures::{image_pair, run_libjxl},
+    };
+
+    /// The largest relative difference from the scores of libjxl's
+    /// `butteraugli_main` which is accepted by [`matches_libjxl`].
+    const LIBJXL_TOLERANCE: f64 = 0.001;
+
+    fn compare(source: &Rgb16Image, distorted: &Rgb16Image) -> Butteraugli {
+        compute_frame_butteraugli(
+            source,
+            distorted,
+            source.width() as usize,
+            source.height() as usize,
+            80.0,
+        )
+    }
+
+    #[test]
+    fn identical_images_score_0() {
+        let (source, _) = image_pair();
+        let result = compare(&source, &source);
+        assert_eq!(result.score, 0.0);
+        assert_eq!(result.norm, 0.0);
+    }
+
+    // The expected values are the output of this implementation, not of
+    // libjxl, pinned so that changes to the constants or filters are noticed.
+
+    #[test]
+    fn pinned_score() {
+        let (source, distorted) = image_pair();
+        let result = compare(&source, &distorted);
+        assert!(
+            (result.score - 1.133_229_255_676_269_5).abs() < 1e-5,
+            "{}",
+            result.score
+        );
+        assert!(
+            (result.norm - 0.802_503_972_162_871_8).abs() < 1e-5,
+            "{}",
+            result.norm
+        );
+    }
+
+    #[test]
+    fn pinned_heatmap() {
+        let (source, distorted) = image_pair();
+        let heatmap = compare(&source, &distorted).heatmap();
+        assert_eq!(heatmap.dimensions(), (64, 48));
+        let sum: u64 = heatmap.iter().map(|&v| v as u64).sum();
+        assert_eq!(sum, 1_381_557);
+        assert_eq!(*heatmap.get_pixel(0, 0), Rgb([0, 255, 241]));
+        assert_eq!(*heatmap.get_pixel(40, 20), Rgb([212, 255, 0]));
+    }
+
+    #[test]
+    fn images_smaller_than_8_pixels() {
+        let (source, distorted) = image_pair();
+        let crop = |image: &Rgb16Image, width, height| {
+            image::imageops::crop_imm(image, 8, 8, width, height).to_image()
+        };
+        for (width, height) in [(4, 4), (3, 12), (12, 5), (1, 1)] {
+            let result = compare(
+                &crop(&source, width, height),
+                &crop(&distorted, width, height),
+            );
+            assert!(result.score > 0.0, "{}x{}", width, height);
+            assert_eq!(
+                result.heatmap().dimensions(),
+                (width, height),
+                "{}x{}",
+                width,
+                height
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "needs libjxl's butteraugli_main binary"]
+    fn matches_libjxl() {
+        let dir = tempfile::tempdir().unwrap();
+        let distmap_path = dir.path().join("distmap.png");
+        let output = run_libjxl("BUTTERAUGLI_PATH", "butteraugli", dir.path(), &[
+            "--distmap".as_ref(),
+            distmap_path.as_ref(),
+            "--intensity_target".as_ref(),
+            "80".as_ref(),
+        ]);
+        let expected_score: f64 = output.lines().next().unwrap().trim().parse().unwrap();
+        let expected_norm: f64 = output
+            .lines()
+            .find_map(|line| line.strip_prefix("3-norm: "))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+
+        let (source, distorted) = image_pair();
+        let result = compare(&source, &distorted);
+        for (value, expected) in [(result.score, expected_score), (result.norm, expected_norm)] {
+            assert!(
+                (value - expected).abs() <= expected * LIBJXL_TOLERANCE,
+                "{} differs from libjxl's {}",
+                value,
+                expected
+            );
+        }
+        let expected_heatmap = image::open(&distmap_path).unwrap().to_rgb8();
+        let heatmap = result.heatmap();
+        assert_eq!(heatmap.dimensions(), expected_heatmap.dimensions());
+        for (a, b) in heatmap.iter().zip(expected_heatmap.iter()) {
+            assert!(a.abs_diff(*b) <= 1, "heatmap differs from libjxl's");
+        }
+    }
+}
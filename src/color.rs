@@ -0,0 +1,909 @@
+//! The colorimetry of the inputs, and conversion of their frames to sRGB.
+//!
+//! Frames are converted from YUV to RGB with the matrix coefficients and range
+//! of their stream. The SDR transfer functions used by video are treated as if
+//! they were sRGB, which keeps the common case of BT.709 content a plain
+//! matrix conversion. Only content whose primaries or transfer function differ
+//! from that is converted through linear light.
+//!
+//! HDR content is converted to the luminance it is displayed at, and scaled
+//! so that the intensity target of the comparison becomes white. Anything
+//! brighter than that is clipped.
+
+use av_metrics::video::{decode::VideoDetails, Frame, Pixel, Plane};
+use ffmpeg_next as ffmpeg;
+use image::{ImageBuffer, Rgb};
+use serde::Serialize;
+
+use crate::{
+    decoder::VideoDecoder,
+    resample::{resample, Filter, Mapping},
+};
+
+/// The coefficients used to derive luma from RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Matrix {
+    Bt601,
+    Bt709,
+    Fcc,
+    Smpte240m,
+    Bt2020,
+}
+
+impl Matrix {
+    pub const NAMES: [&'static str; 5] = ["bt601", "bt709", "fcc", "smpte240m", "bt2020"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bt601" => Some(Matrix::Bt601),
+            "bt709" => Some(Matrix::Bt709),
+            "fcc" => Some(Matrix::Fcc),
+            "smpte240m" => Some(Matrix::Smpte240m),
+            "bt2020" => Some(Matrix::Bt2020),
+            _ => None,
+        }
+    }
+
+    /// Guesses the matrix of an untagged stream from its height.
+    fn guess(height: usize) -> Self {
+        if height > 576 {
+            Matrix::Bt709
+        } else {
+            Matrix::Bt601
+        }
+    }
+
+    fn from_ffmpeg(space: ffmpeg::color::Space, warnings: &mut Vec<String>) -> Option<Self> {
+        use ffmpeg::color::Space;
+        match space {
+            Space::BT470BG | Space::SMPTE170M => Some(Matrix::Bt601),
+            Space::BT709 => Some(Matrix::Bt709),
+            Space::FCC => Some(Matrix::Fcc),
+            Space::SMPTE240M => Some(Matrix::Smpte240m),
+            Space::BT2020NCL => Some(Matrix::Bt2020),
+            Space::Unspecified => None,
+            other => {
+                warnings.push(format!("Unsupported matrix coefficients {:?}", other));
+                None
+            }
+        }
+    }
+
+    /// The weights of red and blue in luma.
+    fn coefficients(self) -> (f32, f32) {
+        match self {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+            Matrix::Fcc => (0.30, 0.11),
+            Matrix::Smpte240m => (0.212, 0.087),
+            Matrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// The range of the YUV samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Range {
+    /// Luma is 16-235 and chroma is 16-240, scaled to the bit depth.
+    Limited,
+    /// Every sample uses the full range of the bit depth.
+    Full,
+}
+
+impl Range {
+    pub const NAMES: [&'static str; 2] = ["limited", "full"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "limited" => Some(Range::Limited),
+            "full" => Some(Range::Full),
+            _ => None,
+        }
+    }
+
+    fn from_ffmpeg(range: ffmpeg::color::Range) -> Option<Self> {
+        match range {
+            ffmpeg::color::Range::MPEG => Some(Range::Limited),
+            ffmpeg::color::Range::JPEG => Some(Range::Full),
+            ffmpeg::color::Range::Unspecified => None,
+        }
+    }
+}
+
+/// The chromaticities of the RGB primaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Primaries {
+    Bt709,
+    /// PAL and SECAM, also used by BT.601 625-line content.
+    Bt470bg,
+    /// NTSC, also used by BT.601 525-line content.
+    Smpte170m,
+    Bt2020,
+}
+
+impl Primaries {
+    pub const NAMES: [&'static str; 4] = ["bt709", "bt470bg", "smpte170m", "bt2020"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bt709" => Some(Primaries::Bt709),
+            "bt470bg" => Some(Primaries::Bt470bg),
+            "smpte170m" => Some(Primaries::Smpte170m),
+            "bt2020" => Some(Primaries::Bt2020),
+            _ => None,
+        }
+    }
+
+    fn from_ffmpeg(
+        primaries: ffmpeg::color::Primaries,
+        warnings: &mut Vec<String>,
+    ) -> Option<Self> {
+        use ffmpeg::color::Primaries as P;
+        match primaries {
+            P::BT709 => Some(Primaries::Bt709),
+            P::BT470BG => Some(Primaries::Bt470bg),
+            P::SMPTE170M | P::SMPTE240M => Some(Primaries::Smpte170m),
+            P::BT2020 => Some(Primaries::Bt2020),
+            P::Unspecified => None,
+            other => {
+                warnings.push(format!("Unsupported color primaries {:?}", other));
+                None
+            }
+        }
+    }
+
+    /// The xy chromaticities of the red, green and blue primaries. All of the
+    /// supported primaries use a D65 white point.
+    fn chromaticities(self) -> [[f64; 2]; 3] {
+        match self {
+            Primaries::Bt709 => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06]],
+            Primaries::Bt470bg => [[0.64, 0.33], [0.29, 0.60], [0.15, 0.06]],
+            Primaries::Smpte170m => [[0.630, 0.340], [0.310, 0.595], [0.155, 0.070]],
+            Primaries::Bt2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+        }
+    }
+
+    /// The matrix converting linear RGB with these primaries to CIE XYZ.
+    fn to_xyz(self) -> [[f64; 3]; 3] {
+        const WHITE: [f64; 2] = [0.3127, 0.3290];
+        let xyz = |[x, y]: [f64; 2]| [x / y, 1.0, (1.0 - x - y) / y];
+        let [r, g, b] = self.chromaticities().map(xyz);
+        let primaries = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+        // Scale the primaries so that they add up to the white point
+        let scale = multiply_vector(invert_3x3(primaries), xyz(WHITE));
+        primaries.map(|row| [row[0] * scale[0], row[1] * scale[1], row[2] * scale[2]])
+    }
+
+    /// The matrix converting linear RGB with these primaries to linear sRGB.
+    fn to_srgb(self) -> [[f32; 3]; 3] {
+        let from = self.to_xyz();
+        let to = invert_3x3(Primaries::Bt709.to_xyz());
+        let mut out = [[0.0; 3]; 3];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, val) in row.iter_mut().enumerate() {
+                *val = (0..3).map(|k| to[i][k] * from[k][j]).sum::<f64>() as f32;
+            }
+        }
+        out
+    }
+}
+
+/// The transfer function of the RGB samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transfer {
+    /// The transfer function of BT.709, BT.601 and SDR BT.2020.
+    Bt709,
+    Srgb,
+    Gamma22,
+    Gamma28,
+    Linear,
+    /// SMPTE ST 2084, used by HDR10.
+    Pq,
+    /// ARIB STD-B67, hybrid log-gamma.
+    Hlg,
+}
+
+impl Transfer {
+    pub const NAMES: [&'static str; 7] =
+        ["bt709", "srgb", "gamma22", "gamma28", "linear", "pq", "hlg"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bt709" => Some(Transfer::Bt709),
+            "srgb" => Some(Transfer::Srgb),
+            "gamma22" => Some(Transfer::Gamma22),
+            "gamma28" => Some(Transfer::Gamma28),
+            "linear" => Some(Transfer::Linear),
+            "pq" => Some(Transfer::Pq),
+            "hlg" => Some(Transfer::Hlg),
+            _ => None,
+        }
+    }
+
+    fn from_ffmpeg(
+        transfer: ffmpeg::color::TransferCharacteristic,
+        warnings: &mut Vec<String>,
+    ) -> Option<Self> {
+        use ffmpeg::color::TransferCharacteristic as T;
+        match transfer {
+            T::BT709 | T::SMPTE170M | T::SMPTE240M | T::BT2020_10 | T::BT2020_12 => {
+                Some(Transfer::Bt709)
+            }
+            T::IEC61966_2_1 => Some(Transfer::Srgb),
+            T::GAMMA22 => Some(Transfer::Gamma22),
+            T::GAMMA28 => Some(Transfer::Gamma28),
+            T::Linear => Some(Transfer::Linear),
+            T::SMPTE2084 => Some(Transfer::Pq),
+            T::ARIB_STD_B67 => Some(Transfer::Hlg),
+            T::Unspecified => None,
+            other => {
+                warnings.push(format!("Unsupported transfer characteristics {:?}", other));
+                None
+            }
+        }
+    }
+
+    /// Whether samples can be passed to the metrics as sRGB without converting
+    /// them.
+    fn is_srgb_like(self) -> bool {
+        matches!(self, Transfer::Bt709 | Transfer::Srgb)
+    }
+
+    pub fn is_hdr(self) -> bool {
+        matches!(self, Transfer::Pq | Transfer::Hlg)
+    }
+
+    /// The luminance in nits which a linear value of 1.0 is displayed at, or
+    /// `None` if the luminance is relative to the display.
+    fn peak_luminance(self) -> Option<f32> {
+        match self {
+            Transfer::Pq => Some(10000.0),
+            Transfer::Hlg => Some(HLG_PEAK_LUMINANCE),
+            _ => None,
+        }
+    }
+
+    /// Linearizes a sample. For HLG, this gives scene light, which still has
+    /// to go through the OOTF.
+    fn to_linear(self, v: f32) -> f32 {
+        match self {
+            Transfer::Bt709 | Transfer::Srgb => srgb_to_linear(v),
+            Transfer::Gamma22 => v.powf(2.2),
+            Transfer::Gamma28 => v.powf(2.8),
+            Transfer::Linear => v,
+            Transfer::Pq => pq_eotf(v),
+            Transfer::Hlg => hlg_inverse_oetf(v),
+        }
+    }
+}
+
+/// The intensity target used when both inputs are SDR. This is the default of
+/// `butteraugli_main`.
+pub const SDR_INTENSITY_TARGET: f32 = 80.0;
+
+/// The intensity target used when either input is HDR. This is the peak
+/// luminance most HDR10 content is mastered for, and the nominal peak of HLG.
+pub const HDR_INTENSITY_TARGET: f32 = 1000.0;
+
+/// The peak luminance of the display HLG content is rendered for.
+const HLG_PEAK_LUMINANCE: f32 = 1000.0;
+
+/// Picks the intensity target for comparing inputs with the given transfer
+/// functions, unless one was given on the command line.
+pub fn default_intensity_target(transfer1: Transfer, transfer2: Transfer) -> f32 {
+    if transfer1.is_hdr() || transfer2.is_hdr() {
+        HDR_INTENSITY_TARGET
+    } else {
+        SDR_INTENSITY_TARGET
+    }
+}
+
+/// The position of subsampled chroma samples relative to the luma samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChromaLocation {
+    /// Horizontally co-sited with the left luma sample and vertically centered,
+    /// as in MPEG-2, H.264 and HEVC.
+    Left,
+    /// Centered between the luma samples, as in JPEG and MPEG-1.
+    Center,
+    /// Co-sited with the top left luma sample, as in BT.2020 and DV.
+    TopLeft,
+    Top,
+    BottomLeft,
+    Bottom,
+}
+
+impl ChromaLocation {
+    pub const NAMES: [&'static str; 6] =
+        ["left", "center", "topleft", "top", "bottomleft", "bottom"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "left" => Some(ChromaLocation::Left),
+            "center" => Some(ChromaLocation::Center),
+            "topleft" => Some(ChromaLocation::TopLeft),
+            "top" => Some(ChromaLocation::Top),
+            "bottomleft" => Some(ChromaLocation::BottomLeft),
+            "bottom" => Some(ChromaLocation::Bottom),
+            _ => None,
+        }
+    }
+
+    fn from_ffmpeg(location: ffmpeg::chroma::Location) -> Option<Self> {
+        use ffmpeg::chroma::Location as L;
+        match location {
+            L::Left => Some(ChromaLocation::Left),
+            L::Center => Some(ChromaLocation::Center),
+            L::TopLeft => Some(ChromaLocation::TopLeft),
+            L::Top => Some(ChromaLocation::Top),
+            L::BottomLeft => Some(ChromaLocation::BottomLeft),
+            L::Bottom => Some(ChromaLocation::Bottom),
+            L::Unspecified => None,
+        }
+    }
+
+    /// The position of the first chroma sample, in luma samples, for the
+    /// given subsampling factors.
+    fn offset(self, (factor_x, factor_y): (usize, usize)) -> (f32, f32) {
+        let (x, y) = match self {
+            ChromaLocation::Left => (0.0, 0.5),
+            ChromaLocation::Center => (0.5, 0.5),
+            ChromaLocation::TopLeft => (0.0, 0.0),
+            ChromaLocation::Top => (0.5, 0.0),
+            ChromaLocation::BottomLeft => (0.0, 1.0),
+            ChromaLocation::Bottom => (0.5, 1.0),
+        };
+        (x * (factor_x - 1) as f32, y * (factor_y - 1) as f32)
+    }
+
+    /// Where each luma sample is in the chroma plane, for upsampling chroma
+    /// with the given subsampling factors.
+    fn mapping(self, factors: (usize, usize)) -> (Mapping, Mapping) {
+        let (offset_x, offset_y) = self.offset(factors);
+        (
+            Mapping {
+                start: -offset_x / factors.0 as f32,
+                step: 1.0 / factors.0 as f32,
+            },
+            Mapping {
+                start: -offset_y / factors.1 as f32,
+                step: 1.0 / factors.1 as f32,
+            },
+        )
+    }
+}
+
+/// How the YUV samples of a stream are converted to RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Colorimetry {
+    pub matrix: Matrix,
+    pub range: Range,
+    pub primaries: Primaries,
+    pub transfer: Transfer,
+    pub chroma_location: ChromaLocation,
+}
+
+impl Colorimetry {
+    /// Reads the colorimetry the video stream of `decoder` is tagged with,
+    /// unless it is overridden.
+    ///
+    /// Untagged streams are assumed to be limited range BT.709, except that
+    /// the matrix of streams which are not taller than 576 lines is assumed to
+    /// be BT.601. HDR streams without tagged primaries are assumed to be
+    /// BT.2020. Untagged chroma is assumed to be sited as in MPEG-2. Tags which
+    /// are not supported are treated as missing, with a warning added to
+    /// `warnings`.
+    pub fn new(
+        decoder: &VideoDecoder,
+        overrides: &ColorOverrides,
+        warnings: &mut Vec<String>,
+    ) -> Self {
+        let details = decoder.video_details();
+        let matrix = overrides
+            .matrix
+            .or_else(|| Matrix::from_ffmpeg(decoder.color_space(), warnings))
+            .unwrap_or_else(|| Matrix::guess(details.height));
+        let range = overrides
+            .range
+            .or_else(|| Range::from_ffmpeg(decoder.color_range()))
+            .unwrap_or(Range::Limited);
+        let transfer = overrides
+            .transfer
+            .or_else(|| Transfer::from_ffmpeg(decoder.color_transfer_characteristic(), warnings))
+            .unwrap_or(Transfer::Bt709);
+        let primaries = overrides
+            .primaries
+            .or_else(|| Primaries::from_ffmpeg(decoder.color_primaries(), warnings))
+            .unwrap_or(if matrix == Matrix::Bt2020 || transfer.is_hdr() {
+                Primaries::Bt2020
+            } else {
+                Primaries::Bt709
+            });
+        let chroma_location = overrides
+            .chroma_location
+            .or_else(|| ChromaLocation::from_ffmpeg(decoder.chroma_location()))
+            .unwrap_or(ChromaLocation::Left);
+        Colorimetry {
+            matrix,
+            range,
+            primaries,
+            transfer,
+            chroma_location,
+        }
+    }
+}
+
+/// Colorimetry given by the user, which takes precedence over the tags of a
+/// stream.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ColorOverrides {
+    pub matrix: Option<Matrix>,
+    pub range: Option<Range>,
+    pub primaries: Option<Primaries>,
+    pub transfer: Option<Transfer>,
+    pub chroma_location: Option<ChromaLocation>,
+}
+
+/// An RGB image with 16 bits per sample.
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+
+/// Converts a frame to 16-bit sRGB, with interleaved RGB samples.
+///
+/// The samples are converted at their full precision, so that high bit depth
+/// inputs are not quantized any further than the output needs.
+///
+/// `intensity_target` is the luminance in nits which HDR content is scaled to
+/// display as white. It has no effect on SDR content. Subsampled chroma is
+/// upsampled to the size of the luma plane with `chroma_filter`.
+pub fn yuv_to_rgb_u16<T: Pixel>(
+    frame: &Frame<T>,
+    details: &VideoDetails,
+    colorimetry: &Colorimetry,
+    intensity_target: f32,
+    chroma_filter: Filter,
+) -> Vec<u16> {
+    let converter = Converter::new(colorimetry, details.bit_depth, intensity_target);
+    let size = (details.width, details.height);
+    let luma = plane_samples(&frame.planes[0], size);
+
+    let (ss_x, ss_y) = match details.chroma_sampling.get_decimation() {
+        Some(decimation) => decimation,
+        None => return luma.iter().flat_map(|&y| converter.gray(y)).collect(),
+    };
+    let chroma_size = (size.0.div_ceil(1 << ss_x), size.1.div_ceil(1 << ss_y));
+    let factors = (1 << ss_x, 1 << ss_y);
+    let mapping = colorimetry.chroma_location.mapping(factors);
+    let upsample = |plane| {
+        let samples = plane_samples(plane, chroma_size);
+        if factors == (1, 1) {
+            samples
+        } else {
+            resample(&samples, chroma_size, size, mapping, chroma_filter)
+        }
+    };
+    let u = upsample(&frame.planes[1]);
+    let v = upsample(&frame.planes[2]);
+
+    luma.iter()
+        .zip(&u)
+        .zip(&v)
+        .flat_map(|((&y, &u), &v)| converter.convert(y, u, v))
+        .collect()
+}
+
+/// Reads the visible `size` samples of a plane. Planes are allocated with a
+/// size rounded up to a multiple of 8, so the plane may be larger than that.
+pub fn plane_samples<T: Pixel>(plane: &Plane<T>, size: (usize, usize)) -> Vec<f32> {
+    (0..size.1)
+        .flat_map(|y| (0..size.0).map(move |x| Into::<u32>::into(plane.p(x, y)) as f32))
+        .collect()
+}
+
+/// Converts YUV samples to sRGB.
+#[derive(Debug, Clone, Copy)]
+struct Converter {
+    y_offset: f32,
+    y_scale: f32,
+    uv_offset: f32,
+    uv_scale: f32,
+    kr: f32,
+    kb: f32,
+    transfer: Transfer,
+    /// The factor linear values are multiplied by to make 1.0 the intensity
+    /// target.
+    linear_scale: f32,
+    /// The contribution of each linear RGB component to luminance.
+    luminance: [f32; 3],
+    /// The conversion of linear RGB to linear sRGB, if the samples can't be
+    /// passed through as they are.
+    gamut: Option<[[f32; 3]; 3]>,
+}
+
+impl Converter {
+    fn new(colorimetry: &Colorimetry, bit_depth: usize, intensity_target: f32) -> Self {
+        let shift = bit_depth - 8;
+        let (y_offset, y_scale, uv_offset, uv_scale) = match colorimetry.range {
+            Range::Limited => (
+                (16 << shift) as f32,
+                1.0 / (219 << shift) as f32,
+                (128 << shift) as f32,
+                1.0 / (224 << shift) as f32,
+            ),
+            Range::Full => {
+                let max = ((1 << bit_depth) - 1) as f32;
+                (0.0, 1.0 / max, (1 << (bit_depth - 1)) as f32, 1.0 / max)
+            }
+        };
+        let (kr, kb) = colorimetry.matrix.coefficients();
+        let passthrough =
+            colorimetry.primaries == Primaries::Bt709 && colorimetry.transfer.is_srgb_like();
+        Converter {
+            y_offset,
+            y_scale,
+            uv_offset,
+            uv_scale,
+            kr,
+            kb,
+            transfer: colorimetry.transfer,
+            linear_scale: colorimetry
+                .transfer
+                .peak_luminance()
+                .map_or(1.0, |peak| peak / intensity_target),
+            luminance: colorimetry.primaries.to_xyz()[1].map(|v| v as f32),
+            gamut: (!passthrough).then(|| colorimetry.primaries.to_srgb()),
+        }
+    }
+
+    fn convert(&self, y: f32, u: f32, v: f32) -> [u16; 3] {
+        let y = (y - self.y_offset) * self.y_scale;
+        let cb = (u - self.uv_offset) * self.uv_scale;
+        let cr = (v - self.uv_offset) * self.uv_scale;
+        let r = y + 2.0 * (1.0 - self.kr) * cr;
+        let b = y + 2.0 * (1.0 - self.kb) * cb;
+        let g = (y - self.kr * r - self.kb * b) / (1.0 - self.kr - self.kb);
+        self.encode([r, g, b])
+    }
+
+    fn gray(&self, y: f32) -> [u16; 3] {
+        let y = (y - self.y_offset) * self.y_scale;
+        self.encode([y, y, y])
+    }
+
+    fn encode(&self, rgb: [f32; 3]) -> [u16; 3] {
+        let rgb = rgb.map(|v| v.clamp(0.0, 1.0));
+        let rgb = match self.gamut {
+            None => rgb,
+            Some(gamut) => {
+                let linear = self.linearize(rgb);
+                gamut.map(|row| {
+                    let v = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+                    linear_to_srgb(v.clamp(0.0, 1.0))
+                })
+            }
+        };
+        rgb.map(|v| (v * u16::MAX as f32).round() as u16)
+    }
+
+    /// Converts samples to linear light, relative to the intensity target.
+    fn linearize(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let linear = rgb.map(|v| self.transfer.to_linear(v));
+        let linear = if self.transfer == Transfer::Hlg {
+            hlg_ootf(linear, self.luminance)
+        } else {
+            linear
+        };
+        linear.map(|v| v * self.linear_scale)
+    }
+}
+
+/// The PQ EOTF, normalized so that 1.0 is 10000 nits.
+fn pq_eotf(v: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+    let p = v.powf(1.0 / M2);
+    ((p - C1).max(0.0) / (C2 - C3 * p)).powf(1.0 / M1)
+}
+
+/// The inverse of the HLG OETF, giving normalized scene light.
+fn hlg_inverse_oetf(v: f32) -> f32 {
+    const A: f32 = 0.178_832_77;
+    const B: f32 = 0.284_668_92;
+    const C: f32 = 0.559_910_7;
+    if v <= 0.5 {
+        v * v / 3.0
+    } else {
+        (((v - C) / A).exp() + B) / 12.0
+    }
+}
+
+/// The HLG OOTF, which maps scene light to display light relative to the peak
+/// luminance of the display.
+fn hlg_ootf(rgb: [f32; 3], luminance: [f32; 3]) -> [f32; 3] {
+    let gamma = 1.2 + 0.42 * (HLG_PEAK_LUMINANCE / 1000.0).log10();
+    let y = luminance[0] * rgb[0] + luminance[1] * rgb[1] + luminance[2] * rgb[2];
+    let scale = if y > 0.0 { y.powf(gamma - 1.0) } else { 0.0 };
+    rgb.map(|v| v * scale)
+}
+
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn multiply_vector(m: [[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+pub fn invert_3x3(m: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let det = m[0][0] * cofactor(1, 2, 1, 2) - m[0][1] * cofactor(1, 2, 0, 2)
+        + m[0][2] * cofactor(1, 2, 0, 1);
+    let inv_det = 1.0 / det;
+    [
+        [
+            cofactor(1, 2, 1, 2) * inv_det,
+            -cofactor(0, 2, 1, 2) * inv_det,
+            cofactor(0, 1, 1, 2) * inv_det,
+        ],
+        [
+            -cofactor(1, 2, 0, 2) * inv_det,
+            cofactor(0, 2, 0, 2) * inv_det,
+            -cofactor(0, 1, 0, 2) * inv_det,
+        ],
+        [
+            cofactor(1, 2, 0, 1) * inv_det,
+            -cofactor(0, 2, 0, 1) * inv_det,
+            cofactor(0, 1, 0, 1) * inv_det,
+        ],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use av_metrics::video::{decode::Rational, ChromaSamplePosition, ChromaSampling};
+
+    use super::*;
+
+    /// Frames whose width is not a multiple of 8, such as 854x480, have planes
+    /// which are wider than the frame. The extra columns must not be read.
+    #[test]
+    fn frame_width_not_multiple_of_8() {
+        let (width, height) = (854, 480);
+        let details = VideoDetails {
+            width,
+            height,
+            bit_depth: 8,
+            chroma_sampling: ChromaSampling::Cs420,
+            chroma_sample_position: ChromaSamplePosition::Unknown,
+            time_base: Rational::new(1, 30),
+            luma_padding: 0,
+        };
+        let colorimetry = Colorimetry {
+            matrix: Matrix::Bt709,
+            range: Range::Limited,
+            primaries: Primaries::Bt709,
+            transfer: Transfer::Bt709,
+            chroma_location: ChromaLocation::Left,
+        };
+
+        // Rows as ffmpeg returns them, with a stride wider than the plane and
+        // junk after the last visible sample. Luma ramps from black to white.
+        let raw_plane = |visible: &dyn Fn(usize) -> u8, width: usize, height: usize| {
+            let stride = width.next_multiple_of(64);
+            let mut data = vec![0u8; stride * height];
+            for row in data.chunks_exact_mut(stride) {
+                for (x, sample) in row[..width].iter_mut().enumerate() {
+                    *sample = visible(x);
+                }
+            }
+            (data, stride)
+        };
+        let luma = raw_plane(&|x| (16 + x * 219 / (width - 1)) as u8, width, height);
+        let chroma = raw_plane(&|_| 128, width.div_ceil(2), height.div_ceil(2));
+        let mut frame: Frame<u8> =
+            Frame::new_with_padding(width, height, details.chroma_sampling, 0);
+        assert!(frame.planes[0].cfg.width > width);
+        for (plane, (data, stride)) in frame.planes.iter_mut().zip([&luma, &chroma, &chroma]) {
+            plane.copy_from_raw_u8(data, *stride, 1);
+        }
+
+        let rgb = yuv_to_rgb_u16(&frame, &details, &colorimetry, 80.0, Filter::Bilinear);
+        assert_eq!(rgb.len(), width * height * 3);
+        let rows: Vec<&[u16]> = rgb.chunks_exact(width * 3).collect();
+        assert!(rows.iter().all(|row| row == &rows[0]), "rows are sheared");
+        assert!(
+            rows[0]
+                .chunks_exact(3)
+                .all(|p| p[0] == p[1] && p[1] == p[2]),
+            "chroma padding was read"
+        );
+        assert_eq!(rows[0][..3], [0; 3]);
+        assert_eq!(rows[0][(width - 1) * 3..], [u16::MAX; 3]);
+    }
+
+    fn sdr_colorimetry(range: Range) -> Colorimetry {
+        Colorimetry {
+            matrix: Matrix::Bt709,
+            range,
+            primaries: Primaries::Bt709,
+            transfer: Transfer::Bt709,
+            chroma_location: ChromaLocation::Left,
+        }
+    }
+
+    #[test]
+    fn limited_range_black_and_white() {
+        let converter = Converter::new(&sdr_colorimetry(Range::Limited), 8, 80.0);
+        assert_eq!(converter.convert(16.0, 128.0, 128.0), [0; 3]);
+        assert_eq!(converter.convert(235.0, 128.0, 128.0), [u16::MAX; 3]);
+        // Footroom and headroom are clipped
+        assert_eq!(converter.gray(4.0), [0; 3]);
+        assert_eq!(converter.gray(250.0), [u16::MAX; 3]);
+
+        let converter = Converter::new(&sdr_colorimetry(Range::Limited), 10, 80.0);
+        assert_eq!(converter.convert(64.0, 512.0, 512.0), [0; 3]);
+        assert_eq!(converter.convert(940.0, 512.0, 512.0), [u16::MAX; 3]);
+    }
+
+    #[test]
+    fn full_range_black_and_white() {
+        let converter = Converter::new(&sdr_colorimetry(Range::Full), 8, 80.0);
+        assert_eq!(converter.convert(0.0, 128.0, 128.0), [0; 3]);
+        assert_eq!(converter.convert(255.0, 128.0, 128.0), [u16::MAX; 3]);
+        // In limited range, 16 would be black
+        assert_ne!(converter.gray(16.0), [0; 3]);
+
+        let converter = Converter::new(&sdr_colorimetry(Range::Full), 10, 80.0);
+        assert_eq!(converter.convert(0.0, 512.0, 512.0), [0; 3]);
+        assert_eq!(converter.convert(1023.0, 512.0, 512.0), [u16::MAX; 3]);
+    }
+
+    #[test]
+    fn pq_reference_white() {
+        // PQ reference white of 100 nits, from BT.2408
+        assert!((pq_eotf(0.5081) * 10000.0 - 100.0).abs() < 0.1);
+        assert_eq!(pq_eotf(0.0), 0.0);
+        assert!((pq_eotf(1.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn hlg_known_values() {
+        assert_eq!(hlg_inverse_oetf(0.0), 0.0);
+        assert!((hlg_inverse_oetf(0.5) - 1.0 / 12.0).abs() < 1e-7);
+        assert!((hlg_inverse_oetf(1.0) - 1.0).abs() < 1e-6);
+    }
+
+    /// White has the same linear value in every gamut with a D65 white point,
+    /// so the rows of the conversion matrices must add up to 1.
+    #[test]
+    fn gamut_matrix_rows_sum_to_1() {
+        for primaries in [
+            Primaries::Bt709,
+            Primaries::Bt470bg,
+            Primaries::Smpte170m,
+            Primaries::Bt2020,
+        ] {
+            for row in primaries.to_srgb() {
+                let sum: f32 = row.iter().sum();
+                assert!((sum - 1.0).abs() < 1e-5, "{:?}: {:?}", primaries, row);
+            }
+            let luminance: f64 = primaries.to_xyz()[1].iter().sum();
+            assert!((luminance - 1.0).abs() < 1e-9, "{:?}", primaries);
+        }
+
+        // The BT.2020 to BT.709 matrix from BT.2087
+        let expected = [[1.6605, -0.5876, -0.0728], [-0.1246, 1.1329, -0.0083], [
+            -0.0182, -0.1006, 1.1187,
+        ]];
+        for (row, expected) in Primaries::Bt2020.to_srgb().iter().zip(expected) {
+            for (value, expected) in row.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-3, "{:?}", row);
+            }
+        }
+    }
+
+    /// Upsamples a step in 2x subsampled chroma, from 0 to 100 between the
+    /// second and third chroma samples, along one dimension.
+    fn upsample_step(location: ChromaLocation, vertical: bool) -> Vec<f32> {
+        let step = [0.0, 0.0, 100.0, 100.0];
+        let (factors, src_size, dst_size) = if vertical {
+            ((1, 2), (1, 4), (1, 8))
+        } else {
+            ((2, 1), (4, 1), (8, 1))
+        };
+        resample(
+            &step,
+            src_size,
+            dst_size,
+            location.mapping(factors),
+            Filter::Bilinear,
+        )
+    }
+
+    #[test]
+    fn chroma_siting_shifts_edge() {
+        // Co-sited chroma samples are at even luma samples, so the edge is
+        // centered on luma sample 3
+        let cosited = [0.0, 0.0, 0.0, 50.0, 100.0, 100.0, 100.0, 100.0];
+        // Centered chroma samples are half a luma sample further on
+        let centered = [0.0, 0.0, 0.0, 25.0, 75.0, 100.0, 100.0, 100.0];
+
+        assert_eq!(upsample_step(ChromaLocation::Left, false), cosited);
+        assert_eq!(upsample_step(ChromaLocation::Left, true), centered);
+        assert_eq!(upsample_step(ChromaLocation::Center, false), centered);
+        assert_eq!(upsample_step(ChromaLocation::Center, true), centered);
+        assert_eq!(upsample_step(ChromaLocation::TopLeft, false), cosited);
+        assert_eq!(upsample_step(ChromaLocation::TopLeft, true), cosited);
+    }
+
+    /// 4:2:2 chroma is only subsampled horizontally, so chroma which varies
+    /// by column and not by row must give rows which are all the same.
+    #[test]
+    fn chroma_422_is_only_subsampled_horizontally() {
+        let (width, height) = (32, 16);
+        let details = VideoDetails {
+            width,
+            height,
+            bit_depth: 8,
+            chroma_sampling: ChromaSampling::Cs422,
+            chroma_sample_position: ChromaSamplePosition::Unknown,
+            time_base: Rational::new(1, 30),
+            luma_padding: 0,
+        };
+        let mut frame: Frame<u8> =
+            Frame::new_with_padding(width, height, details.chroma_sampling, 0);
+        assert_eq!(
+            (frame.planes[1].cfg.width, frame.planes[1].cfg.height),
+            (width / 2, height)
+        );
+        frame.planes[0].data.fill(128);
+        for (x, column) in (0..width / 2).zip((100u8..).step_by(4)) {
+            for y in 0..height {
+                let row = frame.planes[1].row_range(0, y as isize);
+                frame.planes[1].data[row.start + x] = column;
+            }
+        }
+        frame.planes[2].data.fill(128);
+
+        let rgb = yuv_to_rgb_u16(
+            &frame,
+            &details,
+            &sdr_colorimetry(Range::Limited),
+            80.0,
+            Filter::Nearest,
+        );
+        let rows: Vec<&[u16]> = rgb.chunks_exact(width * 3).collect();
+        assert!(rows.iter().all(|row| row == &rows[0]), "rows differ");
+        let pixels: Vec<&[u16]> = rows[0].chunks_exact(3).collect();
+        for pair in pixels.chunks_exact(2) {
+            assert_eq!(pair[0], pair[1]);
+        }
+        for (a, b) in pixels
+            .iter()
+            .step_by(2)
+            .zip(pixels.iter().step_by(2).skip(1))
+        {
+            assert!(b[2] > a[2], "blue does not increase with Cb");
+        }
+    }
+}
@@ -0,0 +1,583 @@
+//! Comparison of two videos, frame by frame.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use av_metrics::video::decode::VideoDetails;
+use image::ImageBuffer;
+use tempfile::Builder;
+
+use crate::{
+    align::{detect_offset, AlignMode, FramePairer, NextPair},
+    color::{default_intensity_target, yuv_to_rgb_u16, ColorOverrides, Colorimetry, Rgb16Image},
+    decoder::{DecodedFrame, Offset, VideoDecoder},
+    distmap::DistmapOutput,
+    dump::{FrameDumper, FrameImages},
+    error::{Error, Result},
+    metric::{FramePair, Metric, MetricScore},
+    report::{CsvLog, FrameScore, Report, StreamInfo},
+    resample::{resize_rgb, Filter, ScaleTo},
+    selection::FrameSelection,
+    stats::StatsOptions,
+};
+
+/// Compares two videos with one or more metrics.
+///
+/// The inputs are decoded, and each pair of frames is converted to RGB, only
+/// once however many metrics they are scored with.
+///
+/// ```no_run
+/// use butter_video::{metric::NativeSsimulacra2, VideoComparator};
+///
+/// let report = VideoComparator::new("source.mkv", "encode.mkv", NativeSsimulacra2)
+///     .threads(4)
+///     .run()
+///     .unwrap();
+/// println!("{}", report.summary.score.mean);
+/// ```
+#[derive(Clone)]
+pub struct VideoComparator {
+    reference: PathBuf,
+    distorted: PathBuf,
+    metrics: Vec<Arc<dyn Metric>>,
+    selection: FrameSelection,
+    threads: usize,
+    align: AlignMode,
+    offset1: Option<Offset>,
+    offset2: Option<Offset>,
+    auto_align: Option<usize>,
+    color1: ColorOverrides,
+    color2: ColorOverrides,
+    intensity_target: Option<f32>,
+    chroma_filter: Filter,
+    scale_to: Option<ScaleTo>,
+    scale_filter: Filter,
+    stats: StatsOptions,
+    worst: usize,
+    csv: Option<PathBuf>,
+    dump_frames: Option<PathBuf>,
+    distmap: Option<PathBuf>,
+}
+
+impl VideoComparator {
+    pub fn new(
+        reference: impl Into<PathBuf>,
+        distorted: impl Into<PathBuf>,
+        metric: impl Metric + 'static,
+    ) -> Self {
+        VideoComparator {
+            reference: reference.into(),
+            distorted: distorted.into(),
+            metrics: vec![Arc::new(metric)],
+            selection: FrameSelection::default(),
+            threads: 1,
+            align: AlignMode::Order,
+            offset1: None,
+            offset2: None,
+            auto_align: None,
+            color1: ColorOverrides::default(),
+            color2: ColorOverrides::default(),
+            intensity_target: None,
+            chroma_filter: Filter::Bilinear,
+            scale_to: None,
+            scale_filter: Filter::Bicubic,
+            stats: StatsOptions::default(),
+            worst: 0,
+            csv: None,
+            dump_frames: None,
+            distmap: None,
+        }
+    }
+
+    /// Also scores the frames with another metric.
+    pub fn metric(mut self, metric: impl Metric + 'static) -> Self {
+        self.metrics.push(Arc::new(metric));
+        self
+    }
+
+    /// Only compares the selected frames. All frames are compared by default.
+    pub fn selection(mut self, selection: FrameSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// The number of frames compared in parallel.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn align(mut self, align: AlignMode) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Skips the start of the reference input.
+    pub fn offset1(mut self, offset: Offset) -> Self {
+        self.offset1 = Some(offset);
+        self
+    }
+
+    /// Skips the start of the distorted input.
+    pub fn offset2(mut self, offset: Offset) -> Self {
+        self.offset2 = Some(offset);
+        self
+    }
+
+    /// Detects the offset between the inputs from their first `window`
+    /// frames, instead of using the offsets which were set.
+    pub fn auto_align(mut self, window: usize) -> Self {
+        self.auto_align = Some(window);
+        self
+    }
+
+    /// Overrides the colorimetry the reference input is tagged with.
+    pub fn color1(mut self, overrides: ColorOverrides) -> Self {
+        self.color1 = overrides;
+        self
+    }
+
+    /// Overrides the colorimetry the distorted input is tagged with.
+    pub fn color2(mut self, overrides: ColorOverrides) -> Self {
+        self.color2 = overrides;
+        self
+    }
+
+    /// The luminance in nits which white is displayed at. By default, this
+    /// depends on whether the inputs are HDR.
+    pub fn intensity_target(mut self, nits: f32) -> Self {
+        self.intensity_target = Some(nits);
+        self
+    }
+
+    pub fn chroma_filter(mut self, filter: Filter) -> Self {
+        self.chroma_filter = filter;
+        self
+    }
+
+    /// Compares inputs with different resolutions at this resolution.
+    pub fn scale_to(mut self, scale_to: ScaleTo) -> Self {
+        self.scale_to = Some(scale_to);
+        self
+    }
+
+    pub fn scale_filter(mut self, filter: Filter) -> Self {
+        self.scale_filter = filter;
+        self
+    }
+
+    /// Additional statistics to summarize the scores with.
+    pub fn stats(mut self, stats: StatsOptions) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// The number of worst scoring frames to list in the report, and to dump
+    /// the images of. The images are of the worst frames by the first metric.
+    pub fn worst(mut self, count: usize) -> Self {
+        self.worst = count;
+        self
+    }
+
+    /// Writes the score of every frame to a CSV file as it is computed.
+    pub fn csv(mut self, path: impl Into<PathBuf>) -> Self {
+        self.csv = Some(path.into());
+        self
+    }
+
+    /// Keeps the images of the worst frames in this directory.
+    pub fn dump_frames(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dump_frames = Some(dir.into());
+        self
+    }
+
+    /// Writes the distortion map of every frame to this directory, or video.
+    /// Only butteraugli produces distortion maps, so only one metric which
+    /// does should be used with this.
+    pub fn distmap(mut self, path: impl Into<PathBuf>) -> Self {
+        self.distmap = Some(path.into());
+        self
+    }
+
+    /// Decodes and compares the inputs, returning the report of the first
+    /// metric.
+    pub fn run(&self) -> Result<Report> {
+        self.run_all()
+            .map(|reports| reports.into_iter().next().unwrap())
+    }
+
+    /// Decodes and compares the inputs, returning a report for each metric in
+    /// the order they were added.
+    pub fn run_all(&self) -> Result<Vec<Report>> {
+        let mut dec1 = VideoDecoder::new(&self.reference)?;
+        let details1 = dec1.video_details();
+        let mut dec2 = VideoDecoder::new(&self.distorted)?;
+        let details2 = dec2.video_details();
+        let detected_offset = match self.auto_align {
+            Some(window) => {
+                let offset = detect_offset(&self.reference, &self.distorted, window)?;
+                dec1.set_offset(Offset::Frames(offset.offset1));
+                dec2.set_offset(Offset::Frames(offset.offset2));
+                Some(offset)
+            }
+            None => {
+                if let Some(offset) = self.offset1 {
+                    dec1.set_offset(offset);
+                }
+                if let Some(offset) = self.offset2 {
+                    dec2.set_offset(offset);
+                }
+                None
+            }
+        };
+        let size1 = (details1.width, details1.height);
+        let size2 = (details2.width, details2.height);
+        let size = match self.scale_to {
+            Some(scale_to) => scale_to.size(size1, size2),
+            None if size1 == size2 => size1,
+            None => {
+                return Err(Error::DimensionMismatch {
+                    reference: size1,
+                    distorted: size2,
+                })
+            }
+        };
+        let mut warnings = vec![];
+        let color1 = Colorimetry::new(&dec1, &self.color1, &mut warnings);
+        let color2 = Colorimetry::new(&dec2, &self.color2, &mut warnings);
+        let intensity_target = self
+            .intensity_target
+            .unwrap_or_else(|| default_intensity_target(color1.transfer, color2.transfer));
+
+        let names: Vec<&str> = self.metrics.iter().map(|metric| metric.name()).collect();
+        let csv = self
+            .csv
+            .as_deref()
+            .map(|path| CsvLog::create(path, &names))
+            .transpose()
+            .map_err(Error::io("Failed to create CSV file"))?;
+        let dumper = self
+            .dump_frames
+            .as_deref()
+            .map(|dir| FrameDumper::new(dir, self.worst, self.metrics[0].direction()))
+            .transpose()
+            .map_err(Error::io("Failed to create frame dump directory"))?;
+        let settings = CompareSettings {
+            metrics: &self.metrics,
+            details1,
+            details2,
+            color1,
+            color2,
+            intensity_target,
+            chroma_filter: self.chroma_filter,
+            size,
+            scale_filter: self.scale_filter,
+            temp_dir: dumper
+                .as_ref()
+                .map_or_else(env::temp_dir, |dumper| dumper.dir().to_path_buf()),
+            keep_images: dumper.is_some(),
+        };
+        let distmap = self
+            .distmap
+            .as_deref()
+            .map(|path| DistmapOutput::new(path, &details1))
+            .transpose()
+            .map_err(Error::io("Failed to create distortion map output"))?;
+        let selection = &self.selection;
+        let threads = self.threads;
+        let mut pairer = FramePairer::new(self.align, details1.time_base.as_f64());
+        let mut collector = FrameCollector {
+            csv,
+            dumper,
+            pending: BTreeMap::new(),
+            recorded: 0,
+            frames: vec![vec![]; self.metrics.len()],
+            error: None,
+        };
+
+        let mut length_mismatch = None;
+        // Frames are decoded on this thread and compared on the worker threads.
+        // Results may arrive out of order, so the collector puts them back in order.
+        thread::scope(|scope| {
+            let (job_tx, job_rx) =
+                crossbeam_channel::bounded::<(usize, FramePosition, FrameJob)>(threads);
+            let (result_tx, result_rx) = crossbeam_channel::unbounded();
+            for _ in 0..threads {
+                let job_rx = job_rx.clone();
+                let result_tx = result_tx.clone();
+                scope.spawn(move || {
+                    for (index, position, job) in job_rx {
+                        if result_tx.send((index, position, job())).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(job_rx);
+            drop(result_tx);
+
+            let mut frameno = 0;
+            let mut index = 0;
+            // Stop decoding as soon as a comparison fails
+            while !selection.is_past_end(frameno) && collector.error.is_none() {
+                let selected = selection.contains(frameno);
+                let distmap_path = distmap
+                    .as_ref()
+                    .filter(|_| selected)
+                    .map(|distmap| distmap.frame_path(frameno));
+                let pair = match pairer.next_pair(&mut dec1, &mut dec2) {
+                    Ok(pair) => pair,
+                    Err(error) => {
+                        collector.error = Some(error);
+                        break;
+                    }
+                };
+                match pair {
+                    NextPair::Pair {
+                        timestamp,
+                        reference,
+                        distorted,
+                    } if selected => {
+                        let settings = &settings;
+                        let job: FrameJob = Box::new(move || {
+                            compare_frame(settings, &reference, &distorted, distmap_path.as_deref())
+                        });
+                        job_tx
+                            .send((index, FramePosition { frameno, timestamp }, job))
+                            .expect("Frame comparison worker exited");
+                        index += 1;
+                    }
+                    // Frames which are not selected still have to be decoded, but
+                    // are not compared
+                    NextPair::Pair { .. } | NextPair::Dropped => (),
+                    NextPair::LengthMismatch => {
+                        length_mismatch = Some(frameno);
+                        break;
+                    }
+                    NextPair::End => break,
+                }
+                frameno += 1;
+
+                for (index, position, result) in result_rx.try_iter() {
+                    collector.add(index, position, result);
+                }
+            }
+            drop(job_tx);
+            for (index, position, result) in result_rx {
+                collector.add(index, position, result);
+            }
+        });
+
+        let FrameCollector {
+            dumper,
+            frames,
+            error,
+            ..
+        } = collector;
+        if let Some(error) = error {
+            return Err(error);
+        }
+        if let Some(ref dumper) = dumper {
+            dumper
+                .finish()
+                .map_err(Error::image("Failed to write frame images"))?;
+        }
+        if let Some(distmap) = distmap {
+            distmap
+                .finish()
+                .map_err(Error::io("Failed to write distortion map video"))?;
+        }
+
+        if frames[0].is_empty() {
+            return Err(Error::NoFrames);
+        }
+        let alignment = pairer.report();
+        Ok(self
+            .metrics
+            .iter()
+            .zip(frames)
+            .map(|(metric, frames)| {
+                Report::new(
+                    metric.name(),
+                    metric.direction(),
+                    StreamInfo::new(&self.reference, &details1, color1),
+                    StreamInfo::new(&self.distorted, &details2, color2),
+                    frames,
+                    &self.stats,
+                    self.worst,
+                )
+                .with_alignment(alignment.clone())
+                .with_detected_offset(detected_offset)
+                .with_length_mismatch(length_mismatch)
+                .with_warnings(warnings.clone())
+            })
+            .collect())
+    }
+}
+
+/// Where a compared pair of frames is in the reference input.
+#[derive(Debug, Clone, Copy)]
+struct FramePosition {
+    frameno: usize,
+    /// The presentation time of the frame, in seconds.
+    timestamp: f64,
+}
+
+/// Receives frame comparisons in any order and records them in frame order.
+struct FrameCollector {
+    csv: Option<CsvLog>,
+    dumper: Option<FrameDumper>,
+    /// Results which arrived before the results of earlier frames, keyed by
+    /// the order in which their frames were decoded.
+    pending: BTreeMap<usize, (FramePosition, Result<FrameComparison>)>,
+    /// The number of results recorded so far.
+    recorded: usize,
+    /// The scores of each metric.
+    frames: Vec<Vec<FrameScore>>,
+    /// The first error, after which no more results are recorded.
+    error: Option<Error>,
+}
+
+impl FrameCollector {
+    fn add(&mut self, index: usize, position: FramePosition, result: Result<FrameComparison>) {
+        if self.error.is_some() {
+            return;
+        }
+        self.pending.insert(index, (position, result));
+        while let Some((position, result)) = self.pending.remove(&self.recorded) {
+            if let Err(error) = self.record(position, result) {
+                self.error = Some(error);
+                return;
+            }
+        }
+    }
+
+    fn record(&mut self, position: FramePosition, result: Result<FrameComparison>) -> Result<()> {
+        let (scores, images) = result?;
+        let frames: Vec<FrameScore> = scores
+            .into_iter()
+            .map(|score| FrameScore {
+                frame: position.frameno,
+                timestamp: position.timestamp,
+                score: score.score,
+                norm: score.norm,
+            })
+            .collect();
+        if let Some(ref mut csv) = self.csv {
+            csv.write_frame(&frames)
+                .map_err(Error::io("Failed to write to CSV file"))?;
+        }
+        if let (Some(dumper), Some(images)) = (&mut self.dumper, images) {
+            dumper
+                .offer(&frames[0], images)
+                .map_err(Error::io("Failed to write frame images"))?;
+        }
+        for (scores, frame) in self.frames.iter_mut().zip(frames) {
+            scores.push(frame);
+        }
+        self.recorded += 1;
+        Ok(())
+    }
+}
+
+/// The score of each metric, and the images that were compared if they are
+/// being kept.
+type FrameComparison = (Vec<MetricScore>, Option<FrameImages>);
+
+/// The comparison of a decoded pair of frames, to be run on a worker thread.
+type FrameJob<'a> = Box<dyn FnOnce() -> Result<FrameComparison> + Send + 'a>;
+
+/// The settings shared by every frame comparison.
+struct CompareSettings<'a> {
+    metrics: &'a [Arc<dyn Metric>],
+    details1: VideoDetails,
+    details2: VideoDetails,
+    color1: Colorimetry,
+    color2: Colorimetry,
+    /// The luminance in nits which white is displayed at.
+    intensity_target: f32,
+    chroma_filter: Filter,
+    /// The resolution frames are compared at, and the filter used to scale
+    /// frames of other resolutions to it.
+    size: (usize, usize),
+    scale_filter: Filter,
+    /// The directory the PNGs of the frames are written to.
+    temp_dir: PathBuf,
+    /// Whether the PNGs of the frames are needed even if no metric needs
+    /// them.
+    keep_images: bool,
+}
+
+fn compare_frame(
+    settings: &CompareSettings,
+    frame1: &DecodedFrame,
+    frame2: &DecodedFrame,
+    distmap: Option<&Path>,
+) -> Result<FrameComparison> {
+    let image1 = to_rgb_image(settings, frame1, &settings.details1, &settings.color1);
+    let image2 = to_rgb_image(settings, frame2, &settings.details2, &settings.color2);
+
+    let needs_images = settings.metrics.iter().any(|metric| metric.needs_images());
+    let images = (settings.keep_images || needs_images)
+        .then(|| write_images(&image1, &image2, &settings.temp_dir))
+        .transpose()?;
+    let frames = FramePair {
+        reference: &image1,
+        distorted: &image2,
+        images: images.as_ref(),
+        intensity_target: settings.intensity_target,
+    };
+    let scores = settings
+        .metrics
+        .iter()
+        .map(|metric| metric.compare(&frames, distmap))
+        .collect::<Result<_>>()?;
+    Ok((scores, images))
+}
+
+/// Converts a frame to RGB at the resolution frames are compared at.
+fn to_rgb_image(
+    settings: &CompareSettings,
+    frame: &DecodedFrame,
+    details: &VideoDetails,
+    colorimetry: &Colorimetry,
+) -> Rgb16Image {
+    let (target, filter) = (settings.intensity_target, settings.chroma_filter);
+    let rgb = match frame {
+        DecodedFrame::Low(frame) => yuv_to_rgb_u16(frame, details, colorimetry, target, filter),
+        DecodedFrame::High(frame) => yuv_to_rgb_u16(frame, details, colorimetry, target, filter),
+    };
+    let size = (details.width, details.height);
+    let rgb = if size == settings.size {
+        rgb
+    } else {
+        resize_rgb(&rgb, size, settings.size, settings.scale_filter)
+    };
+    ImageBuffer::from_raw(settings.size.0 as u32, settings.size.1 as u32, rgb).unwrap()
+}
+
+fn write_images(image1: &Rgb16Image, image2: &Rgb16Image, temp_dir: &Path) -> Result<FrameImages> {
+    let temp_path = || {
+        Builder::new()
+            .suffix(".png")
+            .tempfile_in(temp_dir)
+            .map(|file| file.into_temp_path())
+            .map_err(Error::io("Failed to create frame image"))
+    };
+    let (path1, path2) = (temp_path()?, temp_path()?);
+    image1
+        .save(&path1)
+        .map_err(Error::image("Failed to write frame image"))?;
+    image2
+        .save(&path2)
+        .map_err(Error::image("Failed to write frame image"))?;
+    Ok(FrameImages {
+        reference: path1,
+        distorted: path2,
+    })
+}
@@ -0,0 +1,289 @@
+//! Decoding of the inputs with ffmpeg.
+//!
+//! This follows the decoder from `av-metrics-decoders`, but additionally
+//! accepts the full range `yuvj` pixel formats, respects the stride of the
+//! decoded planes, and exposes the color tags of the stream and the
+//! timestamps of its frames.
+
+use std::path::{Path, PathBuf};
+
+use av_metrics::video::{
+    decode::{Rational, VideoDetails},
+    ChromaSamplePosition,
+    ChromaSampling,
+    Frame,
+    Pixel,
+};
+use ffmpeg::{
+    chroma,
+    codec::{context::Context, decoder},
+    color,
+    format::{context, Pixel as PixelFormat},
+    frame,
+    media::Type,
+    util::error::EAGAIN,
+};
+use ffmpeg_next as ffmpeg;
+
+use crate::error::{Error, Result};
+
+pub struct VideoDecoder {
+    path: PathBuf,
+    input_ctx: context::Input,
+    decoder: decoder::Video,
+    video_details: VideoDetails,
+    full_range_format: bool,
+    stream_index: usize,
+    /// The duration of a tick of the timestamps of the stream, in seconds.
+    stream_time_base: f64,
+    /// The timestamp of the first frame.
+    first_timestamp: Option<i64>,
+    /// The leading frames to skip.
+    offset: Offset,
+    /// The time of the first frame after the offset, relative to the first
+    /// frame, which the returned timestamps are relative to.
+    origin: Option<f64>,
+    frameno: usize,
+    eof_sent: bool,
+}
+
+/// A decoded frame, stored in the smallest pixel type which fits its bit
+/// depth.
+pub enum DecodedFrame {
+    Low(Frame<u8>),
+    High(Frame<u16>),
+}
+
+/// How much of the start of an input to skip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Offset {
+    Frames(usize),
+    Seconds(f64),
+}
+
+impl Offset {
+    /// Parses a number of frames, such as `12`, or of seconds, such as `0.5s`.
+    pub fn parse(val: &str) -> std::result::Result<Self, String> {
+        let invalid = || format!("expected a number of frames or seconds, got `{}`", val);
+        match val.strip_suffix('s') {
+            Some(seconds) => match seconds.parse::<f64>() {
+                Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => {
+                    Ok(Offset::Seconds(seconds))
+                }
+                _ => Err(invalid()),
+            },
+            None => val.parse().map(Offset::Frames).map_err(|_| invalid()),
+        }
+    }
+}
+
+/// A decoded frame and its presentation time.
+pub struct TimedFrame {
+    pub frame: DecodedFrame,
+    /// The presentation time in seconds, relative to the first frame after
+    /// the offset.
+    pub timestamp: f64,
+}
+
+impl VideoDecoder {
+    pub fn new(input: &Path) -> Result<Self> {
+        let error = |message: String| Error::Decode {
+            path: input.to_path_buf(),
+            message,
+        };
+        ffmpeg::init().map_err(|e| error(e.to_string()))?;
+
+        let input_ctx = ffmpeg::format::input(&input).map_err(|e| error(e.to_string()))?;
+        let stream = input_ctx
+            .streams()
+            .best(Type::Video)
+            .ok_or_else(|| error("Could not find video stream".to_string()))?;
+        let stream_index = stream.index();
+        let stream_time_base = f64::from(stream.time_base());
+        // Streams whose average frame rate is unknown report it as 0/0
+        let frame_rate = [stream.avg_frame_rate(), stream.rate()]
+            .into_iter()
+            .find(|rate| rate.numerator() > 0 && rate.denominator() > 0)
+            .unwrap_or_else(|| stream.time_base().invert());
+        let decoder = Context::from_parameters(stream.parameters())
+            .map_err(|e| error(e.to_string()))?
+            .decoder()
+            .video()
+            .map_err(|e| error(e.to_string()))?;
+
+        let (chroma_sampling, bit_depth, full_range_format) = match decoder.format() {
+            PixelFormat::YUV420P => (ChromaSampling::Cs420, 8, false),
+            PixelFormat::YUV422P => (ChromaSampling::Cs422, 8, false),
+            PixelFormat::YUV444P => (ChromaSampling::Cs444, 8, false),
+            PixelFormat::YUVJ420P => (ChromaSampling::Cs420, 8, true),
+            PixelFormat::YUVJ422P => (ChromaSampling::Cs422, 8, true),
+            PixelFormat::YUVJ444P => (ChromaSampling::Cs444, 8, true),
+            PixelFormat::YUV420P10LE => (ChromaSampling::Cs420, 10, false),
+            PixelFormat::YUV422P10LE => (ChromaSampling::Cs422, 10, false),
+            PixelFormat::YUV444P10LE => (ChromaSampling::Cs444, 10, false),
+            PixelFormat::YUV420P12LE => (ChromaSampling::Cs420, 12, false),
+            PixelFormat::YUV422P12LE => (ChromaSampling::Cs422, 12, false),
+            PixelFormat::YUV444P12LE => (ChromaSampling::Cs444, 12, false),
+            format => return Err(error(format!("Unsupported pixel format {:?}", format))),
+        };
+
+        Ok(VideoDecoder {
+            video_details: VideoDetails {
+                width: decoder.width() as usize,
+                height: decoder.height() as usize,
+                bit_depth,
+                chroma_sampling,
+                chroma_sample_position: ChromaSamplePosition::Unknown,
+                time_base: Rational::new(
+                    frame_rate.denominator() as u64,
+                    frame_rate.numerator() as u64,
+                ),
+                luma_padding: 0,
+            },
+            path: input.to_path_buf(),
+            decoder,
+            input_ctx,
+            full_range_format,
+            stream_index,
+            stream_time_base,
+            first_timestamp: None,
+            offset: Offset::Frames(0),
+            origin: None,
+            frameno: 0,
+            eof_sent: false,
+        })
+    }
+
+    pub fn video_details(&self) -> VideoDetails {
+        self.video_details
+    }
+
+    pub fn color_space(&self) -> color::Space {
+        self.decoder.color_space()
+    }
+
+    /// The range of the stream. The `yuvj` pixel formats are always full
+    /// range, even if the stream does not say so.
+    pub fn color_range(&self) -> color::Range {
+        match self.decoder.color_range() {
+            color::Range::Unspecified if self.full_range_format => color::Range::JPEG,
+            range => range,
+        }
+    }
+
+    pub fn color_primaries(&self) -> color::Primaries {
+        self.decoder.color_primaries()
+    }
+
+    pub fn color_transfer_characteristic(&self) -> color::TransferCharacteristic {
+        self.decoder.color_transfer_characteristic()
+    }
+
+    pub fn chroma_location(&self) -> chroma::Location {
+        self.decoder.chroma_location()
+    }
+
+    /// Skips the start of the input. This must be set before reading any
+    /// frames.
+    pub fn set_offset(&mut self, offset: Offset) {
+        self.offset = offset;
+    }
+
+    /// Reads the next frame after the offset, along with its presentation
+    /// time. Returns `None` at the end of the input, and an error if the
+    /// input is corrupt.
+    pub fn read_frame(&mut self) -> Result<Option<TimedFrame>> {
+        loop {
+            let Some(decoded) = self.decode_next()? else {
+                return Ok(None);
+            };
+            let frame_duration = self.video_details.time_base.as_f64();
+            let timestamp = match decoded.timestamp() {
+                Some(timestamp) => {
+                    let first = *self.first_timestamp.get_or_insert(timestamp);
+                    (timestamp - first) as f64 * self.stream_time_base
+                }
+                // Assume a constant frame rate if the frame has no timestamp
+                None => (self.frameno - 1) as f64 * frame_duration,
+            };
+            if self.origin.is_none() {
+                // An offset in seconds starts at the frame nearest to it
+                let skip = match self.offset {
+                    Offset::Frames(frames) => self.frameno <= frames,
+                    Offset::Seconds(seconds) => timestamp < seconds - frame_duration / 2.0,
+                };
+                if skip {
+                    continue;
+                }
+            }
+            let origin = *self.origin.get_or_insert(timestamp);
+            let frame = if self.video_details.bit_depth > 8 {
+                DecodedFrame::High(self.copy_frame(&decoded))
+            } else {
+                DecodedFrame::Low(self.copy_frame(&decoded))
+            };
+            return Ok(Some(TimedFrame {
+                frame,
+                timestamp: timestamp - origin,
+            }));
+        }
+    }
+
+    fn decode_next(&mut self) -> Result<Option<frame::Video>> {
+        let mut decoded = frame::Video::empty();
+        loop {
+            // Drain every frame the decoder has buffered before feeding it
+            // more data, so that no frames are lost at the end of the stream.
+            match self.decoder.receive_frame(&mut decoded) {
+                Ok(()) => {
+                    self.frameno += 1;
+                    return Ok(Some(decoded));
+                }
+                Err(ffmpeg::Error::Eof) => return Ok(None),
+                Err(ffmpeg::Error::Other { errno: EAGAIN }) => (),
+                Err(e) => return Err(self.error(e)),
+            }
+            if self.eof_sent {
+                return Ok(None);
+            }
+
+            match self.input_ctx.packets().next() {
+                Some((stream, mut packet)) => {
+                    if stream.index() != self.stream_index {
+                        continue;
+                    }
+                    if packet.pts().is_none() {
+                        packet.set_pts(Some(self.frameno as i64));
+                        packet.set_dts(Some(self.frameno as i64));
+                    }
+                    self.decoder
+                        .send_packet(&packet)
+                        .map_err(|e| self.error(e))?;
+                }
+                None => {
+                    self.decoder.send_eof().map_err(|e| self.error(e))?;
+                    self.eof_sent = true;
+                }
+            }
+        }
+    }
+
+    /// An error decoding the frame after the last one which was decoded.
+    fn error(&self, error: ffmpeg::Error) -> Error {
+        Error::Decode {
+            path: self.path.clone(),
+            message: format!("frame {}: {}", self.frameno, error),
+        }
+    }
+
+    fn copy_frame<T: Pixel>(&self, decoded: &frame::Video) -> Frame<T> {
+        let details = &self.video_details;
+        let bytes = if details.bit_depth > 8 { 2 } else { 1 };
+        let mut frame: Frame<T> =
+            Frame::new_with_padding(details.width, details.height, details.chroma_sampling, 0);
+        for (i, plane) in frame.planes.iter_mut().enumerate() {
+            plane.copy_from_raw_u8(decoded.data(i), decoded.stride(i), bytes);
+        }
+        frame
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    env,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use av_metrics::video::decode::VideoDetails;
+use image::RgbImage;
+use tempfile::TempDir;
+
+const VIDEO_EXTENSIONS: [&str; 6] = ["mp4", "mkv", "webm", "mov", "avi", "y4m"];
+
+/// Where the butteraugli distortion maps of each frame are written.
+pub enum DistmapOutput {
+    /// One PNG per frame in a directory.
+    Images(PathBuf),
+    /// A video assembled with ffmpeg from PNGs in a temporary directory.
+    Video {
+        frames: TempDir,
+        path: PathBuf,
+        details: VideoDetails,
+    },
+}
+
+impl DistmapOutput {
+    /// Paths with a known video extension produce a video, and any other path
+    /// is treated as a directory.
+    pub fn new(path: &Path, details: &VideoDetails) -> io::Result<Self> {
+        let is_video = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+        if is_video {
+            Ok(DistmapOutput::Video {
+                frames: TempDir::new()?,
+                path: path.to_path_buf(),
+                details: *details,
+            })
+        } else {
+            std::fs::create_dir_all(path)?;
+            Ok(DistmapOutput::Images(path.to_path_buf()))
+        }
+    }
+
+    /// The path to write the distortion map of `frameno` to.
+    pub fn frame_path(&self, frameno: usize) -> PathBuf {
+        match self {
+            DistmapOutput::Images(dir) => map_path(dir, frameno),
+            DistmapOutput::Video { frames, .. } => map_path(frames.path(), frameno),
+        }
+    }
+
+    /// Assembles the distortion maps into a video, if one was requested.
+    pub fn finish(self) -> io::Result<()> {
+        let (frames, path, details) = match self {
+            DistmapOutput::Images(_) => return Ok(()),
+            DistmapOutput::Video {
+                frames,
+                path,
+                details,
+            } => (frames, path, details),
+        };
+
+        fill_gaps(frames.path())?;
+        let ffmpeg_path = env::var("FFMPEG_PATH").unwrap_or_else(|_| "ffmpeg".to_string());
+        // `time_base` is the duration of a frame, so the frame rate is its reciprocal
+        let frame_rate = format!("{}/{}", details.time_base.den, details.time_base.num);
+        let status = Command::new(ffmpeg_path)
+            .args(["-y", "-loglevel", "error", "-framerate", &frame_rate, "-i"])
+            .arg(frames.path().join("distmap_%06d.png"))
+            .arg(&path)
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("ffmpeg exited with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+fn map_path(dir: &Path, frameno: usize) -> PathBuf {
+    dir.join(format!("distmap_{:06}.png", frameno))
+}
+
+/// Fills in the maps of the frames which were not compared, so that the video
+/// stays in sync with the source. ffmpeg also requires an image sequence to be
+/// numbered contiguously. Frames before the first compared frame are blank,
+/// and later frames repeat the map of the last compared frame before them.
+fn fill_gaps(dir: &Path) -> io::Result<()> {
+    let mut framenos: Vec<usize> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name();
+            name.to_str()?
+                .strip_prefix("distmap_")?
+                .strip_suffix(".png")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    framenos.sort_unstable();
+    let (Some(&first), Some(&last)) = (framenos.first(), framenos.last()) else {
+        return Ok(());
+    };
+
+    if first > 0 {
+        let (width, height) =
+            image::image_dimensions(map_path(dir, first)).map_err(io::Error::other)?;
+        RgbImage::new(width, height)
+            .save(map_path(dir, 0))
+            .map_err(io::Error::other)?;
+        for frameno in 1..first {
+            fs::hard_link(map_path(dir, 0), map_path(dir, frameno))?;
+        }
+    }
+    let mut previous = first;
+    for frameno in first + 1..last {
+        if framenos.binary_search(&frameno).is_ok() {
+            previous = frameno;
+        } else {
+            fs::hard_link(map_path(dir, previous), map_path(dir, frameno))?;
+        }
+    }
+    Ok(())
+}
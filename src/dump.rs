@@ -0,0 +1,98 @@
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use image::imageops;
+use tempfile::TempPath;
+
+use crate::{
+    color::Rgb16Image,
+    report::{FrameScore, ScoreDirection},
+};
+
+/// The PNG images a pair of frames was converted to for comparison.
+///
+/// The files are deleted when this is dropped, unless they are kept by a
+/// [`FrameDumper`].
+pub struct FrameImages {
+    pub reference: TempPath,
+    pub distorted: TempPath,
+}
+
+/// Keeps the images of the N worst scoring frames seen so far in a directory.
+pub struct FrameDumper {
+    dir: PathBuf,
+    count: usize,
+    direction: ScoreDirection,
+    /// The frames whose images are currently kept, from worst to best.
+    kept: Vec<FrameScore>,
+}
+
+impl FrameDumper {
+    pub fn new(dir: &Path, count: usize, direction: ScoreDirection) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(FrameDumper {
+            dir: dir.to_path_buf(),
+            count,
+            direction,
+            kept: Vec::with_capacity(count + 1),
+        })
+    }
+
+    /// The directory the images are written to. Temporary images should be
+    /// created here, so that keeping them is a cheap rename.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Keeps the images for `frame` if it is among the worst frames so far,
+    /// evicting the images of the frame it displaces. Otherwise the images are
+    /// deleted.
+    pub fn offer(&mut self, frame: &FrameScore, images: FrameImages) -> io::Result<()> {
+        let pos = self
+            .kept
+            .iter()
+            .position(|kept| self.direction.worst_first(frame.score, kept.score).is_lt())
+            .unwrap_or(self.kept.len());
+        if pos >= self.count {
+            return Ok(());
+        }
+
+        images
+            .reference
+            .persist(self.path_for(frame.frame, "reference"))?;
+        images
+            .distorted
+            .persist(self.path_for(frame.frame, "distorted"))?;
+        self.kept.insert(pos, *frame);
+        if self.kept.len() > self.count {
+            let evicted = self.kept.pop().unwrap();
+            fs::remove_file(self.path_for(evicted.frame, "reference"))?;
+            fs::remove_file(self.path_for(evicted.frame, "distorted"))?;
+        }
+        Ok(())
+    }
+
+    /// Writes a side-by-side composite of the reference and distorted images
+    /// for every kept frame.
+    pub fn finish(&self) -> image::ImageResult<()> {
+        for frame in &self.kept {
+            let reference = image::open(self.path_for(frame.frame, "reference"))?.to_rgb16();
+            let distorted = image::open(self.path_for(frame.frame, "distorted"))?.to_rgb16();
+            let mut composite = Rgb16Image::new(
+                reference.width() + distorted.width(),
+                reference.height().max(distorted.height()),
+            );
+            imageops::replace(&mut composite, &reference, 0, 0);
+            imageops::replace(&mut composite, &distorted, reference.width() as i64, 0);
+            composite.save(self.path_for(frame.frame, "side_by_side"))?;
+        }
+        Ok(())
+    }
+
+    fn path_for(&self, frameno: usize, kind: &str) -> PathBuf {
+        self.dir.join(format!("frame_{:06}_{}.png", frameno, kind))
+    }
+}
@@ -0,0 +1,144 @@
+//! The errors which can stop a comparison.
+
+use std::{fmt, io, path::PathBuf, process::ExitStatus};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// An input could not be opened, is in a format which is not supported, or
+    /// contains a corrupt packet partway through.
+    Decode { path: PathBuf, message: String },
+    /// The inputs have different resolutions, and no resolution to compare
+    /// them at was given.
+    DimensionMismatch {
+        reference: (usize, usize),
+        distorted: (usize, usize),
+    },
+    /// A metric binary could not be run, usually because it is not installed.
+    MetricMissing { command: PathBuf, source: io::Error },
+    /// A metric binary exited with an error.
+    MetricFailed {
+        command: PathBuf,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// The output of a metric binary did not contain a score.
+    UnparsableOutput { command: PathBuf, output: String },
+    /// No frames were compared.
+    NoFrames,
+    /// An output, such as the CSV file or the frame images, could not be
+    /// written.
+    Io {
+        context: &'static str,
+        source: io::Error,
+    },
+    /// The frames are too small for a metric to score.
+    FrameTooSmall {
+        metric: String,
+        size: (usize, usize),
+        minimum: usize,
+    },
+    /// A metric which reads the frames from PNGs was called without them.
+    MissingImages { metric: String },
+}
+
+impl Error {
+    /// The exit code of the process when it fails with this error.
+    ///
+    /// 1 is left for a failed quality gate, and 2 is used by clap for
+    /// invalid arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Decode { .. } => 3,
+            Error::DimensionMismatch { .. } => 4,
+            Error::MetricMissing { .. } => 5,
+            Error::MetricFailed { .. } => 6,
+            Error::UnparsableOutput { .. } => 7,
+            Error::NoFrames => 8,
+            Error::Io { .. } => 9,
+            Error::FrameTooSmall { .. } => 10,
+            Error::MissingImages { .. } => 11,
+        }
+    }
+
+    pub(crate) fn io(context: &'static str) -> impl FnOnce(io::Error) -> Self {
+        move |source| Error::Io { context, source }
+    }
+
+    pub(crate) fn image(context: &'static str) -> impl FnOnce(image::ImageError) -> Self {
+        move |source| Error::Io {
+            context,
+            source: match source {
+                image::ImageError::IoError(source) => source,
+                source => io::Error::other(source),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Decode { path, message } => {
+                write!(f, "Failed to decode {}: {}", path.display(), message)
+            }
+            Error::DimensionMismatch {
+                reference,
+                distorted,
+            } => write!(
+                f,
+                "The inputs have different resolutions ({}x{} and {}x{}). Use --scale-to to \
+                 compare them.",
+                reference.0, reference.1, distorted.0, distorted.1
+            ),
+            Error::MetricMissing { command, source } => {
+                write!(f, "Failed to run {}: {}", command.display(), source)
+            }
+            Error::MetricFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(f, "{} failed ({})", command.display(), status)?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ":\n{}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            Error::UnparsableOutput { command, output } => write!(
+                f,
+                "Could not read a score from the output of {}:\n{}",
+                command.display(),
+                output.trim_end()
+            ),
+            Error::NoFrames => write!(f, "No frames were compared"),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::FrameTooSmall {
+                metric,
+                size,
+                minimum,
+            } => write!(
+                f,
+                "{} needs frames of at least {}x{}, but they are {}x{}",
+                metric, minimum, minimum, size.0, size.1
+            ),
+            Error::MissingImages { metric } => {
+                write!(
+                    f,
+                    "{} needs PNGs of the frames, but none were written",
+                    metric
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MetricMissing { source, .. } | Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
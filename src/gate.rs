@@ -0,0 +1,287 @@
+//! Thresholds on the aggregated score, for use as a quality gate.
+
+use std::fmt;
+
+use crate::{
+    report::Report,
+    stats::{quantile, SeriesStats, Statistic, StatsOptions},
+};
+
+/// The statistic of the per-frame scores a gate is checked against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GateStatistic {
+    Mean,
+    Statistic(Statistic),
+    /// A percentile in the range `0..=100`.
+    Percentile(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// The operators, with the longer ones first so that they are matched
+    /// before their prefixes.
+    const OPERATORS: [(&'static str, Comparison); 4] = [
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ];
+
+    fn holds(self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+        }
+    }
+}
+
+/// A condition on the aggregated score which fails the comparison, such as
+/// `mean>1.5` or `p5<70`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityGate {
+    /// The metric the condition applies to, or every metric if `None`.
+    pub metric: Option<String>,
+    pub statistic: GateStatistic,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// The condition as it was given.
+    text: String,
+}
+
+impl QualityGate {
+    /// Parses a condition made of a statistic, an operator (`<`, `<=`, `>` or
+    /// `>=`) and a threshold. The statistic is `mean`, one of
+    /// [`Statistic::NAMES`], or a percentile such as `p5`. It may be prefixed
+    /// with the name of a metric, as in `ssimulacra2:p5<70`, where `butter`
+    /// is accepted for `butteraugli` like the subcommand.
+    pub fn parse(val: &str) -> Result<Self, String> {
+        let (metric, condition) = match val.split_once(':') {
+            Some((metric, condition)) => {
+                let metric = match metric.trim() {
+                    "" => return Err(format!("missing metric before `:` in `{}`", val)),
+                    "butter" => "butteraugli",
+                    metric => metric,
+                };
+                (Some(metric.to_string()), condition)
+            }
+            None => (None, val),
+        };
+        let (statistic, comparison, threshold) = Comparison::OPERATORS
+            .iter()
+            .find_map(|&(operator, comparison)| {
+                condition
+                    .split_once(operator)
+                    .map(|(statistic, threshold)| (statistic, comparison, threshold))
+            })
+            .ok_or_else(|| format!("expected a condition such as `mean>1.5`, got `{}`", val))?;
+
+        let statistic = match statistic.trim() {
+            "mean" => GateStatistic::Mean,
+            name => match name.strip_prefix('p') {
+                Some(percentile) => match percentile.parse::<f64>() {
+                    Ok(percentile) if (0.0..=100.0).contains(&percentile) => {
+                        GateStatistic::Percentile(percentile)
+                    }
+                    _ => return Err(format!("invalid percentile `{}`", name)),
+                },
+                None => Statistic::from_name(name)
+                    .map(GateStatistic::Statistic)
+                    .ok_or_else(|| format!("unknown statistic `{}`", name))?,
+            },
+        };
+        let threshold = threshold
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("invalid threshold `{}`", threshold.trim()))?;
+        Ok(QualityGate {
+            metric,
+            statistic,
+            comparison,
+            threshold,
+            text: val.to_string(),
+        })
+    }
+
+    /// Computes the statistic over the scores of the report. Percentiles are
+    /// exact, interpolating between the closest scores.
+    pub fn value(&self, report: &Report) -> f64 {
+        let scores: Vec<f64> = report.frames.iter().map(|frame| frame.score).collect();
+        let statistic = match self.statistic {
+            GateStatistic::Mean => None,
+            GateStatistic::Statistic(statistic) => Some(statistic),
+            GateStatistic::Percentile(percentile) => return quantile(&scores, percentile / 100.0),
+        };
+        let options = StatsOptions {
+            statistics: statistic.into_iter().collect(),
+            percentiles: vec![],
+        };
+        let stats = SeriesStats::new(&scores, &options);
+        match statistic {
+            None => stats.mean,
+            Some(Statistic::Min) => stats.min.unwrap(),
+            Some(Statistic::Max) => stats.max.unwrap(),
+            Some(Statistic::StdDev) => stats.std_dev.unwrap(),
+            Some(Statistic::HarmonicMean) => stats.harmonic_mean.unwrap(),
+            Some(Statistic::GeometricMean) => stats.geometric_mean.unwrap(),
+        }
+    }
+
+    /// Returns the value of the statistic if the condition holds, failing the
+    /// gate. A statistic which is not a number, such as the harmonic mean of
+    /// negative scores, always fails. Reports of other metrics always pass.
+    pub fn check(&self, report: &Report) -> Option<f64> {
+        if self
+            .metric
+            .as_ref()
+            .is_some_and(|metric| *metric != report.metric)
+        {
+            return None;
+        }
+        let value = self.value(report);
+        (value.is_nan() || self.comparison.holds(value, self.threshold)).then_some(value)
+    }
+}
+
+impl fmt::Display for QualityGate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use av_metrics::video::decode::VideoDetails;
+
+    use super::*;
+    use crate::{
+        color::{ChromaLocation, Colorimetry, Matrix, Primaries, Range, Transfer},
+        report::{FrameScore, ScoreDirection, StreamInfo},
+    };
+
+    fn report(metric: &str, scores: &[f64]) -> Report {
+        let stream = || {
+            StreamInfo::new(
+                Path::new("input.mkv"),
+                &VideoDetails::default(),
+                Colorimetry {
+                    matrix: Matrix::Bt709,
+                    range: Range::Limited,
+                    primaries: Primaries::Bt709,
+                    transfer: Transfer::Bt709,
+                    chroma_location: ChromaLocation::Left,
+                },
+            )
+        };
+        let frames = scores
+            .iter()
+            .enumerate()
+            .map(|(frame, &score)| FrameScore {
+                frame,
+                timestamp: frame as f64,
+                score,
+                norm: None,
+            })
+            .collect();
+        Report::new(
+            metric,
+            ScoreDirection::HigherIsBetter,
+            stream(),
+            stream(),
+            frames,
+            &StatsOptions::default(),
+            0,
+        )
+    }
+
+    #[test]
+    fn longer_operators_are_matched_first() {
+        let gate = QualityGate::parse("mean<=1.5").unwrap();
+        assert_eq!(gate.comparison, Comparison::LessOrEqual);
+        assert_eq!(gate.threshold, 1.5);
+        let gate = QualityGate::parse("max >= 2").unwrap();
+        assert_eq!(gate.statistic, GateStatistic::Statistic(Statistic::Max));
+        assert_eq!(gate.comparison, Comparison::GreaterOrEqual);
+        let gate = QualityGate::parse("mean<1.5").unwrap();
+        assert_eq!(gate.comparison, Comparison::Less);
+        assert_eq!(gate.threshold, 1.5);
+    }
+
+    #[test]
+    fn percentiles() {
+        let gate = QualityGate::parse("p5<70").unwrap();
+        assert_eq!(gate.statistic, GateStatistic::Percentile(5.0));
+        assert_eq!(
+            QualityGate::parse("p0>1").unwrap().statistic,
+            GateStatistic::Percentile(0.0)
+        );
+        assert_eq!(
+            QualityGate::parse("p99.9>1").unwrap().statistic,
+            GateStatistic::Percentile(99.9)
+        );
+        assert_eq!(
+            QualityGate::parse("p100>1").unwrap().statistic,
+            GateStatistic::Percentile(100.0)
+        );
+        for invalid in ["p101>1", "p-1>1", "p>1", "px>1"] {
+            assert!(QualityGate::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn metric_prefix() {
+        let gate = QualityGate::parse("ssimulacra2:p5<70").unwrap();
+        assert_eq!(gate.metric.as_deref(), Some("ssimulacra2"));
+        assert_eq!(gate.statistic, GateStatistic::Percentile(5.0));
+        assert_eq!(gate.to_string(), "ssimulacra2:p5<70");
+        let gate = QualityGate::parse("butter:mean>1.5").unwrap();
+        assert_eq!(gate.metric.as_deref(), Some("butteraugli"));
+        assert_eq!(QualityGate::parse("mean>1.5").unwrap().metric, None);
+        assert!(QualityGate::parse(":mean>1.5").is_err());
+    }
+
+    #[test]
+    fn invalid_conditions() {
+        for invalid in ["mean", "mean>", "mean>abc", "median>1", ">1", "mean=1"] {
+            assert!(QualityGate::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn check() {
+        let report = report("ssimulacra2", &[90.0, 60.0, 80.0]);
+        assert_eq!(QualityGate::parse("mean<70").unwrap().check(&report), None);
+        assert_eq!(
+            QualityGate::parse("min<70").unwrap().check(&report),
+            Some(60.0)
+        );
+        // The exact median, even with fewer frames than an estimate needs
+        assert_eq!(
+            QualityGate::parse("p50<=80").unwrap().check(&report),
+            Some(80.0)
+        );
+        assert_eq!(
+            QualityGate::parse("butteraugli:min<70")
+                .unwrap()
+                .check(&report),
+            None
+        );
+    }
+
+    #[test]
+    fn check_fails_on_nan() {
+        let report = report("ssimulacra2", &[90.0, -10.0]);
+        let gate = QualityGate::parse("harmonic<0").unwrap();
+        assert!(gate.check(&report).unwrap().is_nan());
+    }
+}
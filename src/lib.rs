@@ -0,0 +1,29 @@
+//! Compares two videos frame by frame using the butteraugli or SSIMULACRA
+//! metrics.
+//!
+//! [`VideoComparator`] decodes both inputs, converts their frames to RGB and
+//! scores every pair with a [`Metric`], returning a [`Report`] of the
+//! per-frame scores and their summary statistics.
+
+#![warn(clippy::all)]
+
+pub mod align;
+mod butteraugli;
+pub mod color;
+mod comparator;
+pub mod decoder;
+mod distmap;
+mod dump;
+pub mod error;
+pub mod gate;
+mod linear_rgb;
+pub mod metric;
+pub mod report;
+pub mod resample;
+pub mod selection;
+mod ssimulacra2;
+pub mod stats;
+#[cfg(test)]
+mod test_fixtures;
+
+pub use crate::{comparator::VideoComparator, metric::Metric, report::Report};
@@ -0,0 +1,42 @@
+use std::sync::OnceLock;
+
+use crate::color::srgb_to_linear;
+
+/// Linear values of every 16-bit sRGB sample, built on first use.
+fn srgb_lut() -> &'static [f32] {
+    static LUT: OnceLock<Vec<f32>> = OnceLock::new();
+    LUT.get_or_init(|| {
+        (0..=u16::MAX)
+            .map(|v| srgb_to_linear(v as f32 / u16::MAX as f32))
+            .collect()
+    })
+}
+
+/// A planar image in linear sRGB.
+pub struct LinearRgb {
+    pub width: usize,
+    pub height: usize,
+    pub planes: [Vec<f32>; 3],
+}
+
+impl LinearRgb {
+    /// Converts 16-bit sRGB with interleaved RGB samples.
+    pub fn from_srgb(data: &[u16], width: usize, height: usize) -> Self {
+        let lut = srgb_lut();
+        let mut planes = [
+            Vec::with_capacity(width * height),
+            Vec::with_capacity(width * height),
+            Vec::with_capacity(width * height),
+        ];
+        for pixel in data.chunks_exact(3) {
+            for (plane, &val) in planes.iter_mut().zip(pixel) {
+                plane.push(lut[val as usize]);
+            }
+        }
+        LinearRgb {
+            width,
+            height,
+            planes,
+        }
+    }
+}
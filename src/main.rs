@@ -1,325 +1,559 @@
 #![warn(clippy::all)]
 
-use std::{env, fs, mem::size_of, path::Path, process::Command};
+use std::{env, path::PathBuf, process};
 
-use av_metrics_decoders::{
-    CastFromPrimitive,
-    ChromaSampling,
-    Decoder,
-    FfmpegDecoder,
-    Frame,
-    Pixel,
-    VideoDetails,
-};
-use average::{Estimate, Quantile};
-use clap::{Arg, ArgMatches};
-use image::{ImageBuffer, RgbImage};
-use tempfile::Builder;
-use yuv::{
-    color::{MatrixCoefficients, Range},
-    convert::RGBConvert,
-    YUV,
+use butter_video::{
+    align::AlignMode,
+    color::{ChromaLocation, ColorOverrides, Matrix, Primaries, Range, Transfer},
+    decoder::Offset,
+    gate::QualityGate,
+    metric::{ExternalMetric, Metric, NativeButteraugli, NativeSsimulacra2},
+    report::{print_json_all, print_text_side_by_side},
+    resample::{Filter, ScaleTo},
+    selection::{parse_frame_list, FrameSelection},
+    stats::{Statistic, StatsOptions},
+    VideoComparator,
 };
+use clap::{Arg, ArgMatches, ErrorKind};
 
 fn main() {
-    let args = clap::Command::new("butter-video")
+    let mut command = clap::Command::new("butter-video")
         .about("Calculates butteraugli and ssimulacra/ssimulacra2 metrics for videos")
         .subcommand(
-            clap::Command::new("butter")
-                .about("Calculate butteraugli score")
-                .arg(Arg::new("input1").required(true).index(1))
-                .arg(Arg::new("input2").required(true).index(2)),
+            metric_command("butter", "Calculate butteraugli score")
+                .arg(
+                    Arg::new("distmap")
+                        .long("distmap")
+                        .help(
+                            "Write the distortion map of every frame as PNGs to this directory, \
+                             or as a video if the path has a video extension",
+                        )
+                        .takes_value(true)
+                        .value_name("DIR|VIDEO"),
+                )
+                .arg(Arg::new("external").long("external").help(
+                    "Use the external butteraugli binary instead of the built-in implementation",
+                )),
         )
+        .subcommand(metric_command("ssimulacra", "Calculate ssimulacra score"))
         .subcommand(
-            clap::Command::new("ssimulacra")
-                .about("Calculate ssimulacra score")
-                .arg(Arg::new("input1").required(true).index(1))
-                .arg(Arg::new("input2").required(true).index(2)),
+            metric_command("ssimulacra2", "Calculate new ssimulacra2 score").arg(
+                Arg::new("external").long("external").help(
+                    "Use the external ssimulacra2 binary instead of the built-in implementation",
+                ),
+            ),
         )
         .subcommand(
-            clap::Command::new("ssimulacra2")
-                .about("Calculate new ssimulacra2 score")
-                .arg(Arg::new("input1").required(true).index(1))
-                .arg(Arg::new("input2").required(true).index(2)),
-        )
-        .get_matches();
+            metric_command(
+                "compare",
+                "Calculate several metrics, decoding and converting the frames only once",
+            )
+            .arg(
+                Arg::new("metrics")
+                    .long("metrics")
+                    .help("Metrics to calculate, e.g. `--metrics butter,ssimulacra2`")
+                    .required(true)
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .use_value_delimiter(true)
+                    .require_value_delimiter(true)
+                    .possible_values(["butter", "butteraugli", "ssimulacra", "ssimulacra2"]),
+            )
+            .arg(Arg::new("external").long("external").help(
+                "Use the external butteraugli and ssimulacra2 binaries instead of the built-in \
+                 implementations",
+            )),
+        );
+    let args = command.get_matches_mut();
 
-    match args.subcommand_name().unwrap() {
-        "butter" => compute_butter(args.subcommand_matches("butter").unwrap()),
-        "ssimulacra" => compute_ssimulacra(args.subcommand_matches("ssimulacra").unwrap()),
-        "ssimulacra2" => compute_ssimulacra2(args.subcommand_matches("ssimulacra2").unwrap()),
-        _ => unreachable!(),
+    let (name, args) = args.subcommand().unwrap();
+    let metrics = match name {
+        "compare" => {
+            // Aliases such as `butter` and `butteraugli` name the same metric
+            let mut metrics: Vec<Box<dyn Metric>> = Vec::new();
+            for name in args.values_of("metrics").unwrap() {
+                let metric = metric(name, args.is_present("external"));
+                if !metrics.iter().any(|other| other.name() == metric.name()) {
+                    metrics.push(metric);
+                }
+            }
+            metrics
+        }
+        "butter" | "ssimulacra2" => vec![metric(name, args.is_present("external"))],
+        _ => vec![metric(name, false)],
     };
-}
+    let distmap = (name == "butter")
+        .then(|| args.value_of("distmap"))
+        .flatten();
 
-fn compute_butter(args: &ArgMatches) {
-    let butteraugli_path =
-        env::var("BUTTERAUGLI_PATH").unwrap_or_else(|_| "butteraugli".to_string());
-    let input1 = Path::new(args.value_of("input1").unwrap());
-    let input2 = Path::new(args.value_of("input2").unwrap());
-    run_metric(&butteraugli_path, input1, input2);
+    let gates: Vec<QualityGate> = args
+        .values_of("fail-if")
+        .into_iter()
+        .flatten()
+        .map(|val| QualityGate::parse(val).unwrap())
+        .collect();
+    let names: Vec<&str> = metrics.iter().map(|metric| metric.name()).collect();
+    for gate in &gates {
+        if let Some(ref metric) = gate.metric {
+            if !names.contains(&metric.as_str()) {
+                command
+                    .find_subcommand_mut(name)
+                    .unwrap()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!(
+                            "Invalid value \"{}\" for '--fail-if <CONDITION>': {} is not one of \
+                             the metrics being calculated ({})",
+                            gate,
+                            metric,
+                            names.join(", ")
+                        ),
+                    )
+                    .exit();
+            }
+        }
+    }
+    run_metrics(metrics, &gates, args, distmap);
 }
 
-fn compute_ssimulacra(args: &ArgMatches) {
-    let ssimulacra_path = env::var("SSIMULACRA_PATH").unwrap_or_else(|_| "ssimulacra".to_string());
-    let input1 = Path::new(args.value_of("input1").unwrap());
-    let input2 = Path::new(args.value_of("input2").unwrap());
-    run_metric(&ssimulacra_path, input1, input2);
+fn metric_command(name: &'static str, about: &'static str) -> clap::Command<'static> {
+    clap::Command::new(name)
+        .about(about)
+        .arg(Arg::new("input1").required(true).index(1))
+        .arg(Arg::new("input2").required(true).index(2))
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .help("Format of the results written to stdout")
+                .takes_value(true)
+                .possible_values(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("csv")
+                .long("csv")
+                .help("Write the score of every frame to a CSV file at this path")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::new("stats")
+                .long("stats")
+                .help("Additional statistics to report for the scores and 3-norms")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true)
+                .require_value_delimiter(true)
+                .possible_values(Statistic::NAMES),
+        )
+        .arg(
+            Arg::new("percentiles")
+                .long("percentiles")
+                .help(
+                    "Percentiles to report for the scores and 3-norms, e.g. `--percentiles \
+                     1,5,50,95,99`",
+                )
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true)
+                .require_value_delimiter(true)
+                .validator(|val| match val.parse::<f64>() {
+                    Ok(p) if (0.0..=100.0).contains(&p) => Ok(()),
+                    _ => Err("must be a number between 0 and 100"),
+                }),
+        )
+        .arg(
+            Arg::new("worst")
+                .long("worst")
+                .help("List the N worst scoring frames")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|val| val.parse::<usize>()),
+        )
+        .arg(
+            Arg::new("fail-if")
+                .long("fail-if")
+                .help(
+                    "Exit with status 1 if a statistic of the scores crosses a threshold, e.g. \
+                     `--fail-if \"mean>1.5\"` or `--fail-if \"p5<70\"`. Prefix the condition with \
+                     a metric, as in `ssimulacra2:p5<70`, to only check that metric. May be given \
+                     more than once",
+                )
+                .takes_value(true)
+                .value_name("CONDITION")
+                .multiple_occurrences(true)
+                .validator(QualityGate::parse),
+        )
+        .arg(
+            Arg::new("start")
+                .long("start")
+                .help("First frame to compare")
+                .takes_value(true)
+                .value_name("FRAME")
+                .validator(|val| val.parse::<usize>()),
+        )
+        .arg(
+            Arg::new("end")
+                .long("end")
+                .help("Last frame to compare, inclusive")
+                .takes_value(true)
+                .value_name("FRAME")
+                .validator(|val| val.parse::<usize>()),
+        )
+        .arg(
+            Arg::new("every")
+                .long("every")
+                .help("Only compare every Nth frame")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|val| match val.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive integer"),
+                }),
+        )
+        .arg(
+            Arg::new("frames")
+                .long("frames")
+                .help("Only compare these frames, e.g. `--frames 10,20,100-200`")
+                .takes_value(true)
+                .value_name("LIST")
+                .validator(parse_frame_list),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .help("Number of frames to compare in parallel")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("1")
+                .validator(|val| match val.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive integer"),
+                }),
+        )
+        .arg(
+            Arg::new("dump-frames")
+                .long("dump-frames")
+                .help(
+                    "Keep the reference, distorted and side-by-side images of the worst frames in \
+                     this directory",
+                )
+                .takes_value(true)
+                .value_name("DIR")
+                .requires("worst"),
+        )
+        .arg(
+            Arg::new("intensity-target")
+                .long("intensity-target")
+                .help(
+                    "Luminance in nits which HDR content is displayed as white at. Brighter \
+                     highlights are clipped. Butteraugli also uses this as the brightness of the \
+                     display. Defaults to 80, or 1000 if either input is HDR",
+                )
+                .takes_value(true)
+                .value_name("NITS")
+                .validator(|val| match val.parse::<f32>() {
+                    Ok(n) if n > 0.0 => Ok(()),
+                    _ => Err("must be a positive number"),
+                }),
+        )
+        .arg(
+            Arg::new("align")
+                .long("align")
+                .help(
+                    "How frames are paired: by decode `order`, or by the `nearest` or `exact` \
+                     matching timestamp. Timestamp alignment reports dropped and duplicated \
+                     frames instead of scoring them",
+                )
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(AlignMode::NAMES)
+                .default_value("order"),
+        )
+        .arg(
+            Arg::new("offset1")
+                .long("offset1")
+                .help(
+                    "Skip the start of the first input, given as a number of frames, or of \
+                     seconds followed by `s`. Frames are numbered from the first frame after the \
+                     offset",
+                )
+                .takes_value(true)
+                .value_name("OFFSET")
+                .validator(Offset::parse),
+        )
+        .arg(
+            Arg::new("offset2")
+                .long("offset2")
+                .help("Skip the start of the second input, like --offset1")
+                .takes_value(true)
+                .value_name("OFFSET")
+                .validator(Offset::parse),
+        )
+        .arg(
+            Arg::new("auto-align")
+                .long("auto-align")
+                .help(
+                    "Find the offset between the inputs by comparing the luma of their first \
+                     frames, and skip the extra frames at the start of one of them",
+                )
+                .conflicts_with_all(&["offset1", "offset2"]),
+        )
+        .arg(
+            Arg::new("auto-align-window")
+                .long("auto-align-window")
+                .help(
+                    "Number of frames of each input --auto-align compares. Offsets of up to half \
+                     of this are detected",
+                )
+                .takes_value(true)
+                .value_name("FRAMES")
+                .default_value("60")
+                .validator(|val| match val.parse::<usize>() {
+                    Ok(frames) if frames >= 2 => Ok(()),
+                    _ => Err("must be at least 2"),
+                }),
+        )
+        .arg(
+            Arg::new("scale-to")
+                .long("scale-to")
+                .help(
+                    "Scale both inputs to the resolution of the `reference` or `distorted` input, \
+                     or to WIDTHxHEIGHT, before comparing them",
+                )
+                .takes_value(true)
+                .value_name("SIZE")
+                .validator(ScaleTo::parse),
+        )
+        .arg(
+            Arg::new("scale-filter")
+                .long("scale-filter")
+                .help("Filter used by --scale-to")
+                .takes_value(true)
+                .value_name("FILTER")
+                .possible_values(Filter::NAMES)
+                .default_value("bicubic"),
+        )
+        .arg(
+            Arg::new("chroma-upsampling")
+                .long("chroma-upsampling")
+                .help("Filter used to upsample subsampled chroma")
+                .takes_value(true)
+                .value_name("FILTER")
+                .possible_values(Filter::NAMES)
+                .default_value("bilinear"),
+        )
+        .args(colorimetry_args(1))
+        .args(colorimetry_args(2))
 }
 
-fn compute_ssimulacra2(args: &ArgMatches) {
-    let ssimulacra2_path = env::var("SSIMULACRA2_PATH").unwrap_or_else(|_| "ssimulacra2".to_string());
-    let input1 = Path::new(args.value_of("input1").unwrap());
-    let input2 = Path::new(args.value_of("input2").unwrap());
-    run_metric(&ssimulacra2_path, input1, input2);
+/// The options which override the colorimetry of the first or second input.
+fn colorimetry_args(input: usize) -> [Arg<'static>; 5] {
+    let [matrix, range, primaries, transfer, chroma_location] = if input == 1 {
+        [
+            "matrix1",
+            "range1",
+            "primaries1",
+            "transfer1",
+            "chroma-location1",
+        ]
+    } else {
+        [
+            "matrix2",
+            "range2",
+            "primaries2",
+            "transfer2",
+            "chroma-location2",
+        ]
+    };
+    [
+        Arg::new(matrix)
+            .long(matrix)
+            .help("Matrix coefficients to use instead of the ones the input is tagged with")
+            .takes_value(true)
+            .value_name("MATRIX")
+            .possible_values(Matrix::NAMES),
+        Arg::new(range)
+            .long(range)
+            .help("Range to use instead of the one the input is tagged with")
+            .takes_value(true)
+            .value_name("RANGE")
+            .possible_values(Range::NAMES),
+        Arg::new(primaries)
+            .long(primaries)
+            .help("Color primaries to use instead of the ones the input is tagged with")
+            .takes_value(true)
+            .value_name("PRIMARIES")
+            .possible_values(Primaries::NAMES),
+        Arg::new(transfer)
+            .long(transfer)
+            .help("Transfer characteristics to use instead of the ones the input is tagged with")
+            .takes_value(true)
+            .value_name("TRANSFER")
+            .possible_values(Transfer::NAMES),
+        Arg::new(chroma_location)
+            .long(chroma_location)
+            .help("Chroma sample location to use instead of the one the input is tagged with")
+            .takes_value(true)
+            .value_name("LOCATION")
+            .possible_values(ChromaLocation::NAMES),
+    ]
 }
 
-fn run_metric(base_command: &str, input1: &Path, input2: &Path) {
-    let mut dec1 = FfmpegDecoder::new(input1).expect("Failed to open file");
-    let details1 = dec1.get_video_details();
-    let mut dec2 = FfmpegDecoder::new(input2).expect("Failed to open file");
-    let details2 = dec2.get_video_details();
-    assert_eq!(details1.height, details2.height);
-    assert_eq!(details1.width, details2.width);
-
-    let mut sum = 0.0f64;
-    let mut norms = vec![];
-    let mut frameno = 0;
-
-    loop {
-        match (details1.bit_depth, details2.bit_depth) {
-            (8, 8) => {
-                let frame1 = dec1.read_video_frame::<u8>();
-                let frame2 = dec2.read_video_frame::<u8>();
-                if frame1.is_none() || frame2.is_none() {
-                    if frame1.is_some() || frame2.is_some() {
-                        eprintln!(
-                            "WARNING: Clips did not match in length! Ending at frame {}",
-                            frameno
-                        );
-                    }
-                    break;
-                }
-                let (score, norm) = compare_frame(
-                    base_command,
-                    &frame1.unwrap(),
-                    &details1,
-                    &frame2.unwrap(),
-                    &details2,
-                );
-                sum += score;
-                if let Some(norm) = norm {
-                    norms.push(norm);
-                }
-            }
-            (8, _) => {
-                let frame1 = dec1.read_video_frame::<u8>();
-                let frame2 = dec2.read_video_frame::<u16>();
-                if frame1.is_none() || frame2.is_none() {
-                    if frame1.is_some() || frame2.is_some() {
-                        eprintln!(
-                            "WARNING: Clips did not match in length! Ending at frame {}",
-                            frameno
-                        );
-                    }
-                    break;
-                }
-                let (score, norm) = compare_frame(
-                    base_command,
-                    &frame1.unwrap(),
-                    &details1,
-                    &frame2.unwrap(),
-                    &details2,
-                );
-                sum += score;
-                if let Some(norm) = norm {
-                    norms.push(norm);
-                }
-            }
-            (_, 8) => {
-                let frame1 = dec1.read_video_frame::<u16>();
-                let frame2 = dec2.read_video_frame::<u8>();
-                if frame1.is_none() || frame2.is_none() {
-                    if frame1.is_some() || frame2.is_some() {
-                        eprintln!(
-                            "WARNING: Clips did not match in length! Ending at frame {}",
-                            frameno
-                        );
-                    }
-                    break;
-                }
-                let (score, norm) = compare_frame(
-                    base_command,
-                    &frame1.unwrap(),
-                    &details1,
-                    &frame2.unwrap(),
-                    &details2,
-                );
-                sum += score;
-                if let Some(norm) = norm {
-                    norms.push(norm);
-                }
-            }
-            (_, _) => {
-                let frame1 = dec1.read_video_frame::<u16>();
-                let frame2 = dec2.read_video_frame::<u16>();
-                if frame1.is_none() || frame2.is_none() {
-                    if frame1.is_some() || frame2.is_some() {
-                        eprintln!(
-                            "WARNING: Clips did not match in length! Ending at frame {}",
-                            frameno
-                        );
-                    }
-                    break;
-                }
-                let (score, norm) = compare_frame(
-                    base_command,
-                    &frame1.unwrap(),
-                    &details1,
-                    &frame2.unwrap(),
-                    &details2,
-                );
-                sum += score;
-                if let Some(norm) = norm {
-                    norms.push(norm);
-                }
-            }
-        };
-
-        frameno += 1;
+fn frame_selection(args: &ArgMatches) -> FrameSelection {
+    FrameSelection {
+        start: args.value_of("start").map_or(0, |n| n.parse().unwrap()),
+        end: args.value_of("end").map(|n| n.parse().unwrap()),
+        every: args.value_of("every").map_or(1, |n| n.parse().unwrap()),
+        frames: args
+            .value_of("frames")
+            .map(|list| parse_frame_list(list).unwrap()),
     }
+}
 
-    if frameno == 0 {
-        panic!("No frames read");
+fn color_overrides(args: &ArgMatches, input: usize) -> ColorOverrides {
+    let value = |name: &str| args.value_of(format!("{}{}", name, input));
+    ColorOverrides {
+        matrix: value("matrix").and_then(Matrix::from_name),
+        range: value("range").and_then(Range::from_name),
+        primaries: value("primaries").and_then(Primaries::from_name),
+        transfer: value("transfer").and_then(Transfer::from_name),
+        chroma_location: value("chroma-location").and_then(ChromaLocation::from_name),
     }
+}
 
-    let avg_score = sum / frameno as f64;
-    println!("Score: {}", avg_score);
-    if !norms.is_empty() {
-        let mut quant = Quantile::new(0.75);
-        for norm in norms {
-            quant.add(norm);
-        }
-        println!("3-norm (75th percentile): {}", quant.quantile());
+fn stats_options(args: &ArgMatches) -> StatsOptions {
+    StatsOptions {
+        statistics: args
+            .values_of("stats")
+            .map(|vals| vals.filter_map(Statistic::from_name).collect())
+            .unwrap_or_default(),
+        percentiles: args
+            .values_of("percentiles")
+            .map(|vals| vals.map(|val| val.parse().unwrap()).collect())
+            .unwrap_or_default(),
     }
 }
 
-fn compare_frame<T: Pixel, U: Pixel>(
-    base_command: &str,
-    frame1: &Frame<T>,
-    details1: &VideoDetails,
-    frame2: &Frame<U>,
-    details2: &VideoDetails,
-) -> (f64, Option<f64>) {
-    let (_, path1) = Builder::new()
-        .suffix(".png")
-        .tempfile()
-        .unwrap()
-        .keep()
-        .unwrap();
-    let (_, path2) = Builder::new()
-        .suffix(".png")
-        .tempfile()
-        .unwrap()
-        .keep()
-        .unwrap();
-    {
-        let image1: RgbImage = ImageBuffer::from_raw(
-            frame1.planes[0].cfg.width as u32,
-            frame1.planes[0].cfg.height as u32,
-            yuv_to_rgb_u8(frame1, details1),
-        )
-        .unwrap();
-        image1.save(&path1).unwrap();
+/// The path of an external metric binary, which may be set with an
+/// environment variable.
+fn binary_path(var: &str, default: &str) -> PathBuf {
+    env::var_os(var).map_or_else(|| PathBuf::from(default), PathBuf::from)
+}
 
-        let image2: RgbImage = ImageBuffer::from_raw(
-            frame2.planes[0].cfg.width as u32,
-            frame2.planes[0].cfg.height as u32,
-            yuv_to_rgb_u8(frame2, details2),
-        )
-        .unwrap();
-        image2.save(&path2).unwrap();
+/// Creates a metric by the name of its subcommand, or the name of the metric.
+fn metric(name: &str, external: bool) -> Box<dyn Metric> {
+    match name {
+        "butter" | "butteraugli" if external => Box::new(ExternalMetric::butteraugli(binary_path(
+            "BUTTERAUGLI_PATH",
+            "butteraugli",
+        ))),
+        "butter" | "butteraugli" => Box::new(NativeButteraugli),
+        "ssimulacra" => Box::new(ExternalMetric::ssimulacra(binary_path(
+            "SSIMULACRA_PATH",
+            "ssimulacra",
+        ))),
+        "ssimulacra2" if external => Box::new(ExternalMetric::ssimulacra2(binary_path(
+            "SSIMULACRA2_PATH",
+            "ssimulacra2",
+        ))),
+        "ssimulacra2" => Box::new(NativeSsimulacra2),
+        _ => unreachable!(),
     }
-    let output = Command::new(base_command)
-        .arg(&path1)
-        .arg(&path2)
-        .output()
-        .unwrap();
-
-    let _ = fs::remove_file(path1);
-    let _ = fs::remove_file(path2);
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let score = stdout
-        .lines()
-        .find(|line| !line.is_empty())
-        .unwrap()
-        .trim()
-        .parse::<f64>()
-        .unwrap();
-    let norm = stdout
-        .lines()
-        .find(|line| line.starts_with("3-norm"))
-        .map(|line| line.split_once(": ").unwrap())
-        .map(|(_, val)| val.parse::<f64>().unwrap());
-    (score, norm)
 }
 
-fn yuv_to_rgb_u8<T: Pixel>(frame: &Frame<T>, details: &VideoDetails) -> Vec<u8> {
-    let plane_y = &frame.planes[0];
-    let plane_u = &frame.planes[1];
-    let plane_v = &frame.planes[2];
-    let bd_shift = details.bit_depth - 8;
+fn run_metrics(
+    metrics: Vec<Box<dyn Metric>>,
+    gates: &[QualityGate],
+    args: &ArgMatches,
+    distmap: Option<&str>,
+) {
+    let mut metrics = metrics.into_iter();
+    let mut comparator = VideoComparator::new(
+        args.value_of("input1").unwrap(),
+        args.value_of("input2").unwrap(),
+        metrics.next().unwrap(),
+    )
+    .selection(frame_selection(args))
+    .threads(args.value_of("threads").unwrap().parse().unwrap())
+    .align(AlignMode::from_name(args.value_of("align").unwrap()).unwrap())
+    .color1(color_overrides(args, 1))
+    .color2(color_overrides(args, 2))
+    .chroma_filter(Filter::from_name(args.value_of("chroma-upsampling").unwrap()).unwrap())
+    .scale_filter(Filter::from_name(args.value_of("scale-filter").unwrap()).unwrap())
+    .stats(stats_options(args))
+    .worst(args.value_of("worst").map_or(0, |n| n.parse().unwrap()));
+    if let Some(offset) = args.value_of("offset1") {
+        comparator = comparator.offset1(Offset::parse(offset).unwrap());
+    }
+    if let Some(offset) = args.value_of("offset2") {
+        comparator = comparator.offset2(Offset::parse(offset).unwrap());
+    }
+    if args.is_present("auto-align") {
+        comparator =
+            comparator.auto_align(args.value_of("auto-align-window").unwrap().parse().unwrap());
+    }
+    if let Some(nits) = args.value_of("intensity-target") {
+        comparator = comparator.intensity_target(nits.parse().unwrap());
+    }
+    if let Some(scale_to) = args.value_of("scale-to") {
+        comparator = comparator.scale_to(ScaleTo::parse(scale_to).unwrap());
+    }
+    if let Some(path) = args.value_of("csv") {
+        comparator = comparator.csv(path);
+    }
+    if let Some(dir) = args.value_of("dump-frames") {
+        comparator = comparator.dump_frames(dir);
+    }
+    if let Some(path) = distmap {
+        comparator = comparator.distmap(path);
+    }
+    for metric in metrics {
+        comparator = comparator.metric(metric);
+    }
 
-    // TODO: Support HDR content
-    let colorspace = if plane_y.cfg.height > 576 {
-        MatrixCoefficients::BT709
-    } else {
-        MatrixCoefficients::BT601
-    };
-    let (ss_x, ss_y) = match details.chroma_sampling {
-        ChromaSampling::Cs400 => {
-            return (0..plane_y.cfg.height)
-                .flat_map(|y| {
-                    (0..plane_y.cfg.width).flat_map(move |x| {
-                        let val = if size_of::<T>() == 1 {
-                            u8::cast_from(plane_y.p(x, y))
-                        } else {
-                            (u16::cast_from(plane_y.p(x, y)) >> bd_shift) as u8
-                        };
-                        [val, val, val].into_iter()
-                    })
-                })
-                .collect();
+    let reports = comparator.run_all().unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        process::exit(e.exit_code());
+    });
+    for warning in &reports[0].warnings {
+        eprintln!("WARNING: {}", warning);
+    }
+    if let Some(frame) = reports[0].length_mismatch {
+        eprintln!(
+            "WARNING: Clips did not match in length! Ending at frame {}. If one of the inputs has \
+             extra frames at the start, skip them with --offset1 or --offset2.",
+            frame
+        );
+    }
+    if let Some(ref alignment) = reports[0].alignment {
+        if !alignment.dropped.is_empty() || !alignment.duplicated.is_empty() {
+            eprintln!(
+                "WARNING: {} reference frames had no matching distorted frame and {} distorted \
+                 frames were duplicates. They were not scored.",
+                alignment.dropped.len(),
+                alignment.duplicated.len()
+            );
         }
-        ChromaSampling::Cs420 => (1, 1),
-        ChromaSampling::Cs422 => (0, 1),
-        ChromaSampling::Cs444 => (0, 0),
-    };
+    }
+    match (args.value_of("output").unwrap(), reports.as_slice()) {
+        ("json", [report]) => report.print_json(),
+        ("json", _) => print_json_all(&reports),
+        (_, [report]) => report.print_text(),
+        _ => print_text_side_by_side(&reports),
+    }
 
-    let converter = RGBConvert::<u8>::new(Range::Limited, colorspace).unwrap();
-    (0..plane_y.cfg.height)
-        .flat_map(|y| {
-            let converter = converter.clone();
-            (0..plane_y.cfg.width).flat_map(move |x| {
-                let (chroma_x, chroma_y) = (x >> ss_x, y >> ss_y);
-                let (y, u, v) = if size_of::<T>() == 1 {
-                    (
-                        u8::cast_from(plane_y.p(x, y)),
-                        u8::cast_from(plane_u.p(chroma_x, chroma_y)),
-                        u8::cast_from(plane_v.p(chroma_x, chroma_y)),
-                    )
-                } else {
-                    (
-                        (u16::cast_from(plane_y.p(x, y)) >> bd_shift) as u8,
-                        (u16::cast_from(plane_u.p(chroma_x, chroma_y)) >> bd_shift) as u8,
-                        (u16::cast_from(plane_v.p(chroma_x, chroma_y)) >> bd_shift) as u8,
-                    )
-                };
-                let yuv = YUV { y, u, v };
-                let rgb = converter.to_rgb(yuv);
-                [rgb.r, rgb.g, rgb.b].into_iter()
-            })
-        })
-        .collect()
+    let mut failed = false;
+    for gate in gates {
+        for report in &reports {
+            if let Some(value) = gate.check(report) {
+                eprintln!("FAILED: {} ({} was {})", gate, report.metric, value);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
 }
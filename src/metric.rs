@@ -0,0 +1,242 @@
+//! The metrics frames can be scored with.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+pub use crate::dump::FrameImages;
+use crate::{
+    butteraugli::compute_frame_butteraugli,
+    color::{Rgb16Image, SDR_INTENSITY_TARGET},
+    error::{Error, Result},
+    report::ScoreDirection,
+    ssimulacra2::{self, compute_frame_ssimulacra2},
+};
+
+/// A pair of frames to be scored, converted to 16-bit sRGB at the resolution
+/// they are compared at.
+pub struct FramePair<'a> {
+    pub reference: &'a Rgb16Image,
+    pub distorted: &'a Rgb16Image,
+    /// PNGs of the frames, if the metric needs them or they are being kept.
+    pub images: Option<&'a FrameImages>,
+    /// The luminance in nits which white is displayed at.
+    pub intensity_target: f32,
+}
+
+/// The score of a pair of frames.
+#[derive(Debug, Clone, Copy)]
+pub struct MetricScore {
+    pub score: f64,
+    /// The 3-norm, for metrics which compute one.
+    pub norm: Option<f64>,
+}
+
+/// A way of scoring how much a distorted frame differs from its reference.
+///
+/// Frames are compared on several threads at once.
+pub trait Metric: Send + Sync {
+    /// The name of the metric, as shown in reports.
+    fn name(&self) -> &str;
+
+    fn direction(&self) -> ScoreDirection;
+
+    /// Whether the metric reads the frames from PNGs, rather than from
+    /// [`FramePair::reference`] and [`FramePair::distorted`].
+    fn needs_images(&self) -> bool {
+        false
+    }
+
+    /// Scores a pair of frames. Metrics which produce a distortion map write
+    /// it to `distmap` as a PNG, if it is given.
+    fn compare(&self, frames: &FramePair, distmap: Option<&Path>) -> Result<MetricScore>;
+}
+
+impl<M: Metric + ?Sized> Metric for Box<M> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn direction(&self) -> ScoreDirection {
+        (**self).direction()
+    }
+
+    fn needs_images(&self) -> bool {
+        (**self).needs_images()
+    }
+
+    fn compare(&self, frames: &FramePair, distmap: Option<&Path>) -> Result<MetricScore> {
+        (**self).compare(frames, distmap)
+    }
+}
+
+/// Computes butteraugli in process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NativeButteraugli;
+
+impl Metric for NativeButteraugli {
+    fn name(&self) -> &str {
+        "butteraugli"
+    }
+
+    fn direction(&self) -> ScoreDirection {
+        ScoreDirection::LowerIsBetter
+    }
+
+    fn compare(&self, frames: &FramePair, distmap: Option<&Path>) -> Result<MetricScore> {
+        let result = compute_frame_butteraugli(
+            frames.reference,
+            frames.distorted,
+            frames.reference.width() as usize,
+            frames.reference.height() as usize,
+            frames.intensity_target,
+        );
+        if let Some(path) = distmap {
+            result
+                .heatmap()
+                .save(path)
+                .map_err(Error::image("Failed to write distortion map"))?;
+        }
+        Ok(MetricScore {
+            score: result.score,
+            norm: Some(result.norm),
+        })
+    }
+}
+
+/// Computes SSIMULACRA2 in process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NativeSsimulacra2;
+
+impl Metric for NativeSsimulacra2 {
+    fn name(&self) -> &str {
+        "ssimulacra2"
+    }
+
+    fn direction(&self) -> ScoreDirection {
+        ScoreDirection::HigherIsBetter
+    }
+
+    fn compare(&self, frames: &FramePair, _distmap: Option<&Path>) -> Result<MetricScore> {
+        let (width, height) = frames.reference.dimensions();
+        let score = compute_frame_ssimulacra2(
+            frames.reference,
+            frames.distorted,
+            width as usize,
+            height as usize,
+        )
+        .ok_or(Error::FrameTooSmall {
+            metric: self.name().to_string(),
+            size: (width as usize, height as usize),
+            minimum: ssimulacra2::MIN_SIZE,
+        })?;
+        Ok(MetricScore { score, norm: None })
+    }
+}
+
+/// Runs a metric binary on PNGs of the frames.
+///
+/// The binary is called with the paths of the reference and distorted
+/// images, and must print the score on the first non-empty line of its
+/// output. A line such as `3-norm: 1.23` is read as the 3-norm.
+#[derive(Debug, Clone)]
+pub struct ExternalMetric {
+    name: String,
+    direction: ScoreDirection,
+    command: PathBuf,
+    /// Whether the binary accepts `--intensity_target`. It is only passed when
+    /// the target differs from the default of 80 nits, since older builds of
+    /// `butteraugli_main` reject it.
+    intensity_target: bool,
+}
+
+impl ExternalMetric {
+    pub fn new(name: &str, direction: ScoreDirection, command: impl Into<PathBuf>) -> Self {
+        ExternalMetric {
+            name: name.to_string(),
+            direction,
+            command: command.into(),
+            intensity_target: false,
+        }
+    }
+
+    /// libjxl's `butteraugli_main`, which is passed the intensity target if it
+    /// is not the SDR default.
+    pub fn butteraugli(command: impl Into<PathBuf>) -> Self {
+        ExternalMetric {
+            intensity_target: true,
+            ..ExternalMetric::new("butteraugli", ScoreDirection::LowerIsBetter, command)
+        }
+    }
+
+    pub fn ssimulacra(command: impl Into<PathBuf>) -> Self {
+        ExternalMetric::new("ssimulacra", ScoreDirection::LowerIsBetter, command)
+    }
+
+    pub fn ssimulacra2(command: impl Into<PathBuf>) -> Self {
+        ExternalMetric::new("ssimulacra2", ScoreDirection::HigherIsBetter, command)
+    }
+}
+
+impl Metric for ExternalMetric {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn direction(&self) -> ScoreDirection {
+        self.direction
+    }
+
+    fn needs_images(&self) -> bool {
+        true
+    }
+
+    fn compare(&self, frames: &FramePair, distmap: Option<&Path>) -> Result<MetricScore> {
+        let images = frames.images.ok_or_else(|| Error::MissingImages {
+            metric: self.name.clone(),
+        })?;
+        let mut command = Command::new(&self.command);
+        command.arg(&images.reference).arg(&images.distorted);
+        if let Some(distmap) = distmap {
+            command.arg("--distmap").arg(distmap);
+        }
+        if self.intensity_target && frames.intensity_target != SDR_INTENSITY_TARGET {
+            command
+                .arg("--intensity_target")
+                .arg(frames.intensity_target.to_string());
+        }
+        let output = command.output().map_err(|source| Error::MetricMissing {
+            command: self.command.clone(),
+            source,
+        })?;
+        if !output.status.success() {
+            return Err(Error::MetricFailed {
+                command: self.command.clone(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let unparsable = || Error::UnparsableOutput {
+            command: self.command.clone(),
+            output: stdout.to_string(),
+        };
+        let score = stdout
+            .lines()
+            .find(|line| !line.is_empty())
+            .and_then(|line| line.trim().parse::<f64>().ok())
+            .ok_or_else(unparsable)?;
+        let norm = stdout
+            .lines()
+            .find(|line| line.starts_with("3-norm"))
+            .map(|line| {
+                line.split_once(": ")
+                    .and_then(|(_, val)| val.trim().parse::<f64>().ok())
+                    .ok_or_else(unparsable)
+            })
+            .transpose()?;
+        Ok(MetricScore { score, norm })
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use av_metrics::video::{decode::VideoDetails, ChromaSampling};
+use serde::Serialize;
+
+use crate::{
+    align::{AlignmentReport, DetectedOffset},
+    color::Colorimetry,
+    stats::{quantile, SeriesStats, StatsOptions},
+};
+
+/// The results of comparing a single pair of frames.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FrameScore {
+    pub frame: usize,
+    /// The presentation timestamp of the frame, in seconds.
+    pub timestamp: f64,
+    pub score: f64,
+    /// The 3-norm reported by the metric, if the metric reports one.
+    pub norm: Option<f64>,
+}
+
+/// The properties of one of the compared video streams.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamInfo {
+    pub path: String,
+    pub width: usize,
+    pub height: usize,
+    pub bit_depth: usize,
+    pub chroma_sampling: &'static str,
+    /// The duration of a single frame, in seconds, as a `[numerator,
+    /// denominator]` pair.
+    pub time_base: [u64; 2],
+    /// How the frames were converted to RGB.
+    pub colorimetry: Colorimetry,
+}
+
+impl StreamInfo {
+    pub fn new(path: &Path, details: &VideoDetails, colorimetry: Colorimetry) -> Self {
+        StreamInfo {
+            path: path.to_string_lossy().into_owned(),
+            width: details.width,
+            height: details.height,
+            bit_depth: details.bit_depth,
+            chroma_sampling: match details.chroma_sampling {
+                ChromaSampling::Cs420 => "4:2:0",
+                ChromaSampling::Cs422 => "4:2:2",
+                ChromaSampling::Cs444 => "4:4:4",
+                ChromaSampling::Cs400 => "4:0:0",
+            },
+            time_base: [details.time_base.num, details.time_base.den],
+            colorimetry,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub frames: usize,
+    pub score: SeriesStats,
+    /// Statistics for the 3-norms, if the metric reports them.
+    pub norm: Option<SeriesStats>,
+    /// The 75th percentile of all 3-norms, if the metric reports them.
+    pub norm_p75: Option<f64>,
+}
+
+impl Summary {
+    fn new(frames: &[FrameScore], options: &StatsOptions) -> Self {
+        let scores: Vec<f64> = frames.iter().map(|f| f.score).collect();
+        let norms: Vec<f64> = frames.iter().filter_map(|f| f.norm).collect();
+        let (norm, norm_p75) = if norms.is_empty() {
+            (None, None)
+        } else {
+            (
+                Some(SeriesStats::new(&norms, options)),
+                Some(quantile(&norms, 0.75)),
+            )
+        };
+        Summary {
+            frames: frames.len(),
+            score: SeriesStats::new(&scores, options),
+            norm,
+            norm_p75,
+        }
+    }
+}
+
+/// Whether a metric considers higher or lower scores to be better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreDirection {
+    LowerIsBetter,
+    HigherIsBetter,
+}
+
+impl ScoreDirection {
+    /// Orders scores from worst to best.
+    pub fn worst_first(self, a: f64, b: f64) -> Ordering {
+        match self {
+            ScoreDirection::LowerIsBetter => b.total_cmp(&a),
+            ScoreDirection::HigherIsBetter => a.total_cmp(&b),
+        }
+    }
+}
+
+/// The complete results of comparing two videos.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub metric: String,
+    pub direction: ScoreDirection,
+    pub input1: StreamInfo,
+    pub input2: StreamInfo,
+    pub frames: Vec<FrameScore>,
+    pub summary: Summary,
+    /// The worst scoring frames, from worst to best.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub worst_frames: Vec<FrameScore>,
+    /// The frames which could not be paired, if frames were aligned by
+    /// timestamp.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alignment: Option<AlignmentReport>,
+    /// The offset between the inputs, if it was detected automatically.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detected_offset: Option<DetectedOffset>,
+    /// The frame at which the comparison ended because one input ran out of
+    /// frames before the other.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length_mismatch: Option<usize>,
+    /// Problems with the inputs which did not stop the comparison, such as
+    /// unsupported color tags.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn new(
+        metric: &str,
+        direction: ScoreDirection,
+        input1: StreamInfo,
+        input2: StreamInfo,
+        frames: Vec<FrameScore>,
+        options: &StatsOptions,
+        worst: usize,
+    ) -> Self {
+        let summary = Summary::new(&frames, options);
+        let mut worst_frames = frames.clone();
+        worst_frames.sort_by(|a, b| direction.worst_first(a.score, b.score));
+        worst_frames.truncate(worst);
+        Report {
+            metric: metric.to_string(),
+            direction,
+            input1,
+            input2,
+            frames,
+            summary,
+            worst_frames,
+            alignment: None,
+            detected_offset: None,
+            length_mismatch: None,
+            warnings: vec![],
+        }
+    }
+
+    pub fn with_alignment(mut self, alignment: Option<AlignmentReport>) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    pub fn with_detected_offset(mut self, detected_offset: Option<DetectedOffset>) -> Self {
+        self.detected_offset = detected_offset;
+        self
+    }
+
+    pub fn with_length_mismatch(mut self, length_mismatch: Option<usize>) -> Self {
+        self.length_mismatch = length_mismatch;
+        self
+    }
+
+    pub fn with_warnings(mut self, warnings: Vec<String>) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    pub fn print_text(&self) {
+        for (label, value) in self.summary_rows() {
+            println!("{}: {}", label, value);
+        }
+        self.print_alignment_text();
+        self.print_worst_frames_text("Worst frames:");
+    }
+
+    /// The mean score and every requested statistic, with their labels.
+    fn summary_rows(&self) -> Vec<(String, f64)> {
+        let mut rows = vec![("Score".to_string(), self.summary.score.mean)];
+        rows.extend(self.summary.score.rows("Score"));
+        if let Some(norm) = self.summary.norm_p75 {
+            rows.push(("3-norm (75th percentile)".to_string(), norm));
+        }
+        if let Some(ref norm) = self.summary.norm {
+            rows.extend(norm.rows("3-norm"));
+        }
+        rows
+    }
+
+    fn print_alignment_text(&self) {
+        if let Some(ref alignment) = self.alignment {
+            println!("Dropped frames: {}", alignment.dropped.len());
+            println!("Duplicated frames: {}", alignment.duplicated.len());
+        }
+        if let Some(ref offset) = self.detected_offset {
+            println!(
+                "Detected offset: skipped {} frames of input 1 and {} frames of input 2 (luma \
+                 PSNR {:.2} dB)",
+                offset.offset1, offset.offset2, offset.psnr
+            );
+        }
+    }
+
+    fn print_worst_frames_text(&self, title: &str) {
+        if !self.worst_frames.is_empty() {
+            println!("{}", title);
+            for frame in &self.worst_frames {
+                println!(
+                    "  Frame {} ({}): {}",
+                    frame.frame,
+                    format_timestamp(frame.timestamp),
+                    frame.score
+                );
+            }
+        }
+    }
+
+    pub fn print_json(&self) {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(self).expect("Failed to serialize report")
+        );
+    }
+}
+
+/// Prints the results of several metrics over the same frames, with a
+/// column for each metric.
+pub fn print_text_side_by_side(reports: &[Report]) {
+    // Every report has the same statistics, except that only some metrics
+    // have 3-norms
+    let mut rows: Vec<(String, Vec<Option<f64>>)> = Vec::new();
+    for (i, report) in reports.iter().enumerate() {
+        for (label, value) in report.summary_rows() {
+            let row = match rows.iter().position(|(row, _)| *row == label) {
+                Some(row) => row,
+                None => {
+                    rows.push((label, vec![None; reports.len()]));
+                    rows.len() - 1
+                }
+            };
+            rows[row].1[i] = Some(value);
+        }
+    }
+
+    let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let columns: Vec<Vec<String>> = (0..reports.len())
+        .map(|i| {
+            rows.iter()
+                .map(|(_, values)| values[i].map_or_else(|| "-".to_string(), |v| v.to_string()))
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = reports
+        .iter()
+        .zip(&columns)
+        .map(|(report, column)| {
+            column
+                .iter()
+                .map(String::len)
+                .chain([report.metric.len()])
+                .max()
+                .unwrap()
+        })
+        .collect();
+
+    print!("{:label_width$}", "");
+    for (report, width) in reports.iter().zip(&widths) {
+        print!("  {:>width$}", report.metric);
+    }
+    println!();
+    for (row, (label, _)) in rows.iter().enumerate() {
+        print!("{:label_width$}", label);
+        for (column, width) in columns.iter().zip(&widths) {
+            print!("  {:>width$}", column[row]);
+        }
+        println!();
+    }
+
+    if let Some(report) = reports.first() {
+        report.print_alignment_text();
+    }
+    for report in reports {
+        report.print_worst_frames_text(&format!("Worst frames by {}:", report.metric));
+    }
+}
+
+/// Prints the results of several metrics over the same frames as a JSON
+/// array of their reports.
+pub fn print_json_all(reports: &[Report]) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(reports).expect("Failed to serialize report")
+    );
+}
+
+/// Formats a number of seconds as `HH:MM:SS.mmm`.
+fn format_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Writes one CSV row per frame as the frames are compared.
+pub struct CsvLog {
+    writer: BufWriter<File>,
+}
+
+impl CsvLog {
+    /// Creates the log for the scores of the given metrics. With a single
+    /// metric, its columns are named `score` and `3-norm`.
+    pub fn create(path: &Path, metrics: &[&str]) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "frame,timestamp")?;
+        match metrics {
+            [_] => write!(writer, ",score,3-norm")?,
+            _ => {
+                for metric in metrics {
+                    write!(writer, ",{0},{0} 3-norm", metric)?;
+                }
+            }
+        }
+        writeln!(writer)?;
+        Ok(CsvLog { writer })
+    }
+
+    /// Writes the scores of every metric for one frame.
+    pub fn write_frame(&mut self, frames: &[FrameScore]) -> io::Result<()> {
+        write!(
+            self.writer,
+            "{},{:.6}",
+            frames[0].frame, frames[0].timestamp
+        )?;
+        for frame in frames {
+            write!(self.writer, ",{}", frame.score)?;
+            match frame.norm {
+                Some(norm) => write!(self.writer, ",{}", norm)?,
+                None => write!(self.writer, ",")?,
+            }
+        }
+        writeln!(self.writer)?;
+        // Flush every row so the log is usable even if the run is interrupted
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,271 @@
+//! Resampling of planes with separable filters.
+
+use std::f32::consts::PI;
+
+/// The filter used to interpolate between samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    /// Catmull-Rom.
+    Bicubic,
+    /// Lanczos with 3 lobes.
+    Lanczos,
+}
+
+impl Filter {
+    pub const NAMES: [&'static str; 4] = ["nearest", "bilinear", "bicubic", "lanczos"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Filter::Nearest),
+            "bilinear" => Some(Filter::Bilinear),
+            "bicubic" => Some(Filter::Bicubic),
+            "lanczos" => Some(Filter::Lanczos),
+            _ => None,
+        }
+    }
+
+    /// How far from its center the kernel is non-zero.
+    fn support(self) -> f32 {
+        match self {
+            Filter::Nearest => 0.5,
+            Filter::Bilinear => 1.0,
+            Filter::Bicubic => 2.0,
+            Filter::Lanczos => 3.0,
+        }
+    }
+
+    fn kernel(self, x: f32) -> f32 {
+        match self {
+            // Half open, so that a sample halfway between two inputs picks
+            // exactly one of them
+            Filter::Nearest if (-0.5..0.5).contains(&x) => 1.0,
+            Filter::Nearest => 0.0,
+            Filter::Bilinear => (1.0 - x.abs()).max(0.0),
+            Filter::Bicubic => {
+                let x = x.abs();
+                if x < 1.0 {
+                    (1.5 * x - 2.5) * x * x + 1.0
+                } else if x < 2.0 {
+                    ((-0.5 * x + 2.5) * x - 4.0) * x + 2.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Lanczos if x.abs() < 3.0 => sinc(x) * sinc(x / 3.0),
+            Filter::Lanczos => 0.0,
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Where the output samples are along one dimension of the input: output
+/// sample `i` is at input position `start + i * step`.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub start: f32,
+    pub step: f32,
+}
+
+impl Mapping {
+    /// Scales `src_len` samples to `dst_len` samples, lining up the edges of
+    /// the first and last samples.
+    pub fn scale(src_len: usize, dst_len: usize) -> Self {
+        let step = src_len as f32 / dst_len as f32;
+        Mapping {
+            start: 0.5 * step - 0.5,
+            step,
+        }
+    }
+}
+
+/// The resolution frames are compared at, if the inputs differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleTo {
+    Reference,
+    Distorted,
+    Size(usize, usize),
+}
+
+impl ScaleTo {
+    /// Parses `reference`, `distorted`, or a size such as `1920x1080`.
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val {
+            "reference" => return Ok(ScaleTo::Reference),
+            "distorted" => return Ok(ScaleTo::Distorted),
+            _ => (),
+        }
+        let parse = |n: &str| match n.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("invalid size `{}`", val)),
+        };
+        match val.split_once('x') {
+            Some((width, height)) => Ok(ScaleTo::Size(parse(width)?, parse(height)?)),
+            None => Err(format!(
+                "expected `reference`, `distorted` or WIDTHxHEIGHT, got `{}`",
+                val
+            )),
+        }
+    }
+
+    pub fn size(self, reference: (usize, usize), distorted: (usize, usize)) -> (usize, usize) {
+        match self {
+            ScaleTo::Reference => reference,
+            ScaleTo::Distorted => distorted,
+            ScaleTo::Size(width, height) => (width, height),
+        }
+    }
+}
+
+/// Resizes an image with interleaved 16-bit RGB samples.
+pub fn resize_rgb(
+    data: &[u16],
+    src_size: (usize, usize),
+    dst_size: (usize, usize),
+    filter: Filter,
+) -> Vec<u16> {
+    let mapping = (
+        Mapping::scale(src_size.0, dst_size.0),
+        Mapping::scale(src_size.1, dst_size.1),
+    );
+    let channels = [0, 1, 2].map(|c| {
+        let plane: Vec<f32> = data.iter().skip(c).step_by(3).map(|&v| v as f32).collect();
+        resample(&plane, src_size, dst_size, mapping, filter)
+    });
+    (0..dst_size.0 * dst_size.1)
+        .flat_map(|i| {
+            channels
+                .each_ref()
+                .map(|channel| channel[i].round().clamp(0.0, u16::MAX as f32) as u16)
+        })
+        .collect()
+}
+
+/// Resamples a plane of `src_width` by `src_height` samples to `dst_width` by
+/// `dst_height` samples.
+///
+/// Samples outside of the plane repeat the samples at its edges. When
+/// downscaling, the filter is stretched to avoid aliasing.
+pub fn resample(
+    src: &[f32],
+    (src_width, src_height): (usize, usize),
+    (dst_width, dst_height): (usize, usize),
+    (x, y): (Mapping, Mapping),
+    filter: Filter,
+) -> Vec<f32> {
+    let x_taps = taps(filter, src_width, dst_width, x);
+    let y_taps = taps(filter, src_height, dst_height, y);
+
+    let mut horizontal = Vec::with_capacity(dst_width * src_height);
+    for row in src.chunks_exact(src_width) {
+        horizontal.extend(x_taps.iter().map(|taps| apply(taps, |i| row[i])));
+    }
+
+    let mut out = Vec::with_capacity(dst_width * dst_height);
+    for taps in &y_taps {
+        out.extend((0..dst_width).map(|x| apply(taps, |i| horizontal[i * dst_width + x])));
+    }
+    out
+}
+
+/// The input samples, and their weights, which make up each output sample
+/// along one dimension.
+fn taps(
+    filter: Filter,
+    src_len: usize,
+    dst_len: usize,
+    mapping: Mapping,
+) -> Vec<Vec<(usize, f32)>> {
+    let stretch = if filter == Filter::Nearest {
+        1.0
+    } else {
+        mapping.step.max(1.0)
+    };
+    let support = filter.support() * stretch;
+    (0..dst_len)
+        .map(|i| {
+            let center = mapping.start + i as f32 * mapping.step;
+            let first = (center - support).floor() as isize;
+            let last = (center + support).ceil() as isize;
+            let mut taps: Vec<(usize, f32)> = (first..=last)
+                .filter_map(|j| {
+                    let weight = filter.kernel((j as f32 - center) / stretch);
+                    (weight != 0.0).then(|| (j.clamp(0, src_len as isize - 1) as usize, weight))
+                })
+                .collect();
+            let sum: f32 = taps.iter().map(|&(_, weight)| weight).sum();
+            if taps.is_empty() || sum == 0.0 {
+                let nearest = center.round().clamp(0.0, (src_len - 1) as f32) as usize;
+                return vec![(nearest, 1.0)];
+            }
+            for (_, weight) in &mut taps {
+                *weight /= sum;
+            }
+            taps
+        })
+        .collect()
+}
+
+fn apply(taps: &[(usize, f32)], sample: impl Fn(usize) -> f32) -> f32 {
+    taps.iter().map(|&(i, weight)| sample(i) * weight).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 4] = [
+        Filter::Nearest,
+        Filter::Bilinear,
+        Filter::Bicubic,
+        Filter::Lanczos,
+    ];
+
+    #[test]
+    fn constant_plane_is_reproduced() {
+        let src = vec![1000.0; 12 * 10];
+        for filter in FILTERS {
+            for dst_size in [(12, 10), (25, 21), (5, 3)] {
+                let mapping = (
+                    Mapping::scale(12, dst_size.0),
+                    Mapping::scale(10, dst_size.1),
+                );
+                let dst = resample(&src, (12, 10), dst_size, mapping, filter);
+                assert_eq!(dst.len(), dst_size.0 * dst_size.1);
+                assert!(
+                    dst.iter().all(|&v| (v - 1000.0).abs() < 1e-3),
+                    "{:?} to {:?}",
+                    filter,
+                    dst_size
+                );
+
+                let rgb: Vec<u16> = [12345, 0, u16::MAX].repeat(12 * 10);
+                let resized = resize_rgb(&rgb, (12, 10), dst_size, filter);
+                assert_eq!(
+                    resized,
+                    [12345, 0, u16::MAX].repeat(dst_size.0 * dst_size.1)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn same_size_is_unchanged() {
+        let src: Vec<f32> = (0..12 * 10).map(|v| (v * 37 % 101) as f32).collect();
+        for filter in FILTERS {
+            let mapping = (Mapping::scale(12, 12), Mapping::scale(10, 10));
+            let dst = resample(&src, (12, 10), (12, 10), mapping, filter);
+            for (a, b) in dst.iter().zip(&src) {
+                assert!((a - b).abs() < 1e-3, "{:?}", filter);
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::ops::RangeInclusive;
+
+/// Which frames of the inputs should be compared.
+///
+/// Every condition must hold for a frame to be selected.
+#[derive(Debug, Clone, Default)]
+pub struct FrameSelection {
+    /// The first frame to compare.
+    pub start: usize,
+    /// The last frame to compare, inclusive.
+    pub end: Option<usize>,
+    /// Only compare every Nth frame, counting from `start`.
+    pub every: usize,
+    /// Only compare frames in this list.
+    pub frames: Option<FrameList>,
+}
+
+impl FrameSelection {
+    pub fn contains(&self, frameno: usize) -> bool {
+        frameno >= self.start
+            && self.end.is_none_or(|end| frameno <= end)
+            && (frameno - self.start).is_multiple_of(self.every.max(1))
+            && self
+                .frames
+                .as_ref()
+                .is_none_or(|frames| frames.contains(frameno))
+    }
+
+    /// Returns `true` if no frames at or after `frameno` are selected, so
+    /// decoding can stop early.
+    pub fn is_past_end(&self, frameno: usize) -> bool {
+        self.end.is_some_and(|end| frameno > end)
+            || self
+                .frames
+                .as_ref()
+                .is_some_and(|frames| frames.last().is_none_or(|last| frameno > last))
+    }
+}
+
+/// A list of frame numbers, stored as sorted ranges which do not overlap so
+/// that long ranges don't take up any memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameList {
+    ranges: Vec<RangeInclusive<usize>>,
+}
+
+impl FrameList {
+    pub fn contains(&self, frameno: usize) -> bool {
+        let index = self.ranges.partition_point(|range| *range.end() < frameno);
+        self.ranges
+            .get(index)
+            .is_some_and(|range| range.contains(&frameno))
+    }
+
+    /// The last frame in the list.
+    pub fn last(&self) -> Option<usize> {
+        self.ranges.last().map(|range| *range.end())
+    }
+}
+
+/// Parses a comma separated list of frame numbers and inclusive ranges, e.g.
+/// `10,20,100-200`.
+pub fn parse_frame_list(list: &str) -> Result<FrameList, String> {
+    let mut ranges = Vec::new();
+    for item in list
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        let parse = |val: &str| {
+            val.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid frame number `{}`", val))
+        };
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(format!("invalid frame range `{}`", item));
+                }
+                ranges.push(first..=last);
+            }
+            None => {
+                let frameno = parse(item)?;
+                ranges.push(frameno..=frameno);
+            }
+        }
+    }
+
+    ranges.sort_by_key(|range| *range.start());
+    let mut merged: Vec<RangeInclusive<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                *last = *last.start()..=*range.end().max(last.end());
+            }
+            _ => merged.push(range),
+        }
+    }
+    Ok(FrameList { ranges: merged })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_list_contains() {
+        let frames = parse_frame_list("10,20,100-200,150-250,5").unwrap();
+        for frameno in [5, 10, 20, 100, 200, 201, 250] {
+            assert!(frames.contains(frameno), "{}", frameno);
+        }
+        for frameno in [0, 6, 11, 99, 251] {
+            assert!(!frames.contains(frameno), "{}", frameno);
+        }
+        assert_eq!(frames.last(), Some(250));
+    }
+
+    #[test]
+    fn huge_frame_range() {
+        let frames = parse_frame_list("0-4000000000").unwrap();
+        assert!(frames.contains(3_999_999_999));
+        assert!(!frames.contains(4_000_000_001));
+
+        let selection = FrameSelection {
+            frames: Some(frames),
+            ..FrameSelection::default()
+        };
+        assert!(!selection.is_past_end(4_000_000_000));
+        assert!(selection.is_past_end(4_000_000_001));
+    }
+
+    #[test]
+    fn invalid_frame_lists() {
+        assert!(parse_frame_list("5-3").is_err());
+        assert!(parse_frame_list("a").is_err());
+        assert!(parse_frame_list("1-").is_err());
+    }
+
+    #[test]
+    fn selection_conditions() {
+        let selection = FrameSelection {
+            start: 10,
+            end: Some(30),
+            every: 5,
+            frames: Some(parse_frame_list("0-20,30").unwrap()),
+        };
+        let selected: Vec<usize> = (0..40).filter(|&n| selection.contains(n)).collect();
+        assert_eq!(selected, [10, 15, 20, 30]);
+        assert!(!selection.is_past_end(30));
+        assert!(selection.is_past_end(31));
+    }
+}
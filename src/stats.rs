@@ -0,0 +1,193 @@
+use average::{Max, Mean, Min, Variance};
+use serde::Serialize;
+
+/// An optional statistic which may be reported in addition to the mean.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Statistic {
+    Min,
+    Max,
+    StdDev,
+    HarmonicMean,
+    GeometricMean,
+}
+
+impl Statistic {
+    pub const NAMES: [&'static str; 5] = ["min", "max", "stddev", "harmonic", "geometric"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "min" => Some(Statistic::Min),
+            "max" => Some(Statistic::Max),
+            "stddev" => Some(Statistic::StdDev),
+            "harmonic" => Some(Statistic::HarmonicMean),
+            "geometric" => Some(Statistic::GeometricMean),
+            _ => None,
+        }
+    }
+}
+
+/// Which statistics to compute for each series of values.
+#[derive(Debug, Clone, Default)]
+pub struct StatsOptions {
+    pub statistics: Vec<Statistic>,
+    /// Percentiles to compute, in the range `0..=100`.
+    pub percentiles: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PercentileValue {
+    pub percentile: f64,
+    pub value: f64,
+}
+
+/// Summary statistics for a series of per-frame values.
+///
+/// Statistics which were not requested are `None`. The harmonic and geometric
+/// means are only defined for positive values, and are `NaN` otherwise.
+#[derive(Debug, Clone, Serialize)]
+pub struct SeriesStats {
+    pub mean: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub std_dev: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub harmonic_mean: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geometric_mean: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub percentiles: Vec<PercentileValue>,
+}
+
+impl SeriesStats {
+    pub fn new(values: &[f64], options: &StatsOptions) -> Self {
+        let wants = |stat| options.statistics.contains(&stat);
+        let mean: Mean = values.iter().collect();
+        SeriesStats {
+            mean: mean.mean(),
+            min: wants(Statistic::Min).then(|| values.iter().collect::<Min>().min()),
+            max: wants(Statistic::Max).then(|| values.iter().collect::<Max>().max()),
+            std_dev: wants(Statistic::StdDev)
+                .then(|| values.iter().collect::<Variance>().sample_variance().sqrt()),
+            harmonic_mean: wants(Statistic::HarmonicMean).then(|| harmonic_mean(values)),
+            geometric_mean: wants(Statistic::GeometricMean).then(|| geometric_mean(values)),
+            percentiles: options
+                .percentiles
+                .iter()
+                .map(|&percentile| PercentileValue {
+                    percentile,
+                    value: quantile(values, percentile / 100.0),
+                })
+                .collect(),
+        }
+    }
+
+    /// Every requested statistic, excluding the mean, with a label starting
+    /// with `label`.
+    pub fn rows(&self, label: &str) -> Vec<(String, f64)> {
+        let stats = [
+            ("min", self.min),
+            ("max", self.max),
+            ("std dev", self.std_dev),
+            ("harmonic mean", self.harmonic_mean),
+            ("geometric mean", self.geometric_mean),
+        ];
+        let mut rows: Vec<(String, f64)> = stats
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (format!("{} {}", label, name), value)))
+            .collect();
+        rows.extend(self.percentiles.iter().map(|p| {
+            (
+                format!("{} ({} percentile)", label, ordinal(p.percentile)),
+                p.value,
+            )
+        }));
+        rows
+    }
+}
+
+/// The exact `p` quantile of `values`, for `p` in the range `0..=1`,
+/// interpolating linearly between the closest ranks.
+pub fn quantile(values: &[f64], p: f64) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let rank = p.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+fn harmonic_mean(values: &[f64]) -> f64 {
+    if values.iter().any(|&v| v <= 0.0) {
+        return f64::NAN;
+    }
+    let mean: Mean = values.iter().map(|v| 1.0 / v).collect();
+    1.0 / mean.mean()
+}
+
+fn geometric_mean(values: &[f64]) -> f64 {
+    if values.iter().any(|&v| v <= 0.0) {
+        return f64::NAN;
+    }
+    let mean: Mean = values.iter().map(|v| v.ln()).collect();
+    mean.mean().exp()
+}
+
+fn ordinal(percentile: f64) -> String {
+    if percentile.fract() != 0.0 {
+        return format!("{}th", percentile);
+    }
+    let n = percentile as u64;
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantile_of_few_unsorted_values() {
+        let values = [3.0, 1.0, 2.0];
+        assert_eq!(quantile(&values, 0.0), 1.0);
+        assert_eq!(quantile(&values, 0.5), 2.0);
+        assert_eq!(quantile(&values, 1.0), 3.0);
+    }
+
+    #[test]
+    fn quantile_interpolates_between_ranks() {
+        let values = [40.0, 10.0, 30.0, 20.0];
+        assert_eq!(quantile(&values, 0.5), 25.0);
+        assert_eq!(quantile(&values, 0.75), 32.5);
+        assert!((quantile(&values, 0.05) - 11.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn quantile_of_single_and_no_values() {
+        assert_eq!(quantile(&[7.0], 0.05), 7.0);
+        assert!(quantile(&[], 0.5).is_nan());
+    }
+
+    #[test]
+    fn percentiles_are_exact() {
+        let values: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        let options = StatsOptions {
+            statistics: vec![],
+            percentiles: vec![1.0, 50.0, 99.0],
+        };
+        let stats = SeriesStats::new(&values, &options);
+        let percentiles: Vec<f64> = stats.percentiles.iter().map(|p| p.value).collect();
+        assert_eq!(percentiles, [1.99, 50.5, 99.01]);
+    }
+}
@@ -0,0 +1,45 @@
+//! Images shared by the tests of the native metrics.
+
+use std::{env, ffi::OsStr, path::Path, process::Command};
+
+use image::Rgb;
+
+use crate::color::Rgb16Image;
+
+/// A 64x48 image of gradients and edges, and a copy of it with noise.
+pub fn image_pair() -> (Rgb16Image, Rgb16Image) {
+    let source = Rgb16Image::from_fn(64, 48, |x, y| {
+        let edge = if (x / 8 + y / 8) % 2 == 0 { 12000 } else { 0 };
+        Rgb([
+            (x * 700 + edge) as u16,
+            (y * 900 + edge) as u16,
+            ((x + y) * 400 + 8000) as u16,
+        ])
+    });
+    let distorted = Rgb16Image::from_fn(64, 48, |x, y| {
+        let noise = ((x * 31 + y * 17) % 13) as u16 * 150;
+        let Rgb([r, g, b]) = *source.get_pixel(x, y);
+        Rgb([r.saturating_add(noise), g, b.saturating_sub(noise)])
+    });
+    (source, distorted)
+}
+
+/// Runs a libjxl binary on PNGs of [`image_pair`] written to `dir`, followed by
+/// `args`, and returns what it printed. The binary is looked up at the path in
+/// `path_var`, or in the PATH as `name`.
+pub fn run_libjxl(path_var: &str, name: &str, dir: &Path, args: &[&OsStr]) -> String {
+    let (source, distorted) = image_pair();
+    let (source_path, distorted_path) = (dir.join("source.png"), dir.join("distorted.png"));
+    source.save(&source_path).unwrap();
+    distorted.save(&distorted_path).unwrap();
+
+    let command = env::var(path_var).unwrap_or_else(|_| name.to_string());
+    let output = Command::new(command)
+        .arg(&source_path)
+        .arg(&distorted_path)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{} failed", name);
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}